@@ -1,6 +1,17 @@
 use std::collections::HashMap;
 
 use clap::Parser;
+use regex::Regex;
+
+use crate::mpris::connection::BlockList;
+
+/// An --icon-format value: a glyph, or a glyph with the color it gets under --markup.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum IconEntry {
+    Glyph(String),
+    Colored { glyph: String, color: String },
+}
 
 /// Position display mode for track time.
 #[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
@@ -9,72 +20,407 @@ pub enum PositionMode {
     Increasing,
     /// Show remaining time
     Remaining,
+    /// Show elapsed and total time, like 01:23/04:56
+    #[value(alias = "elapsed-total")]
+    Full,
+    /// Show a progress bar of block glyphs, with eighth-cell precision
+    FineBar,
+}
+/// Which side of the text the icon goes on.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum IconPosition {
+    Left,
+    Right,
+}
+/// Playback states in which the position is shown.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum PositionWhen {
+    Always,
+    Paused,
+    Playing,
+}
+/// What a frozen (paused) scroll shows.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum FreezeMode {
+    /// Keep the frame that was on screen when playback paused
+    InPlace,
+    /// Show the start of the text
+    Start,
+}
+/// Resolution of the position display.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum PositionPrecision {
+    /// Whole seconds, like 01:23
+    S,
+    /// Milliseconds, like 01:23.456
+    Ms,
+}
+/// Shape of each printed line.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// JSON object with text, class and tooltip (Waybar's custom module format)
+    #[value(alias = "json")]
+    Waybar,
+    /// Just the text, for bars that read plain lines
+    Plain,
+}
+/// Letter case applied to the formatted metadata.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum TextCase {
+    /// Leave the text as the player sent it
+    None,
+    /// UPPERCASE
+    Upper,
+    /// lowercase
+    Lower,
+    /// Capitalize The First Letter Of Each Word
+    Title,
+}
+/// Where short text sits within the --min-width padding.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Align {
+    Left,
+    /// Split the padding evenly, the extra space going to the right
+    Center,
+    Right,
 }
-pub use crate::scroll::ScrollMode;
+pub use crate::scroll::{RESET_HOLD, ScrollMode, WRAP_GAP};
 
 /// Configuration parsed from command-line arguments.
 #[derive(Debug, Parser, Clone)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_override_self = true)]
 pub struct Config {
+    /// TOML file of default options, keyed by long option name; reloaded on SIGHUP
+    #[arg(long = "config")]
+    pub config_file: Option<String>,
     /// Scroll speed (0: slow=1000ms, 100: fast=100ms)
-    #[arg(short = 's', long = "speed", default_value_t = 0)]
+    #[arg(short = 's', long = "speed", env = "SCROLLMPRIS_SPEED", default_value_t = 0, value_parser = clap::value_parser!(u32).range(0..=100))]
     pub speed: u32,
+    /// Scale the scroll delay so a full cycle takes about the same time for any text length
+    #[arg(long = "adaptive-speed", default_value_t = false, action = clap::ArgAction::SetTrue)]
+    pub adaptive_speed: bool,
     /// Maximum width for the scrolling text
-    #[arg(short = 'w', long = "width", default_value_t = 40)]
+    #[arg(short = 'w', long = "width", env = "SCROLLMPRIS_WIDTH", default_value_t = 40)]
     pub width: usize,
     /// Block certain players (comma-separated list)
     #[arg(
         short = 'b',
         long = "blocked",
+        env = "SCROLLMPRIS_BLOCKED",
         value_delimiter = ',',
         default_value = ""
     )]
     pub blocked: Vec<String>,
+    /// Also block the players listed in this file, one per line (# starts a comment line)
+    #[arg(long = "blocked-file")]
+    pub blocked_file: Option<String>,
+    /// Match --blocked names against the whole player base name instead of any part of the service
+    #[arg(long = "blocked-exact", default_value_t = false, action = clap::ArgAction::SetTrue)]
+    pub blocked_exact: bool,
+    /// Block players whose full service name matches a regex (repeatable)
+    #[arg(long = "blocked-regex", action = clap::ArgAction::Append)]
+    blocked_regex: Vec<String>,
+    #[arg(skip)]
+    pub blocked_patterns: Vec<Regex>,
     /// Scrolling behavior: "wrapping" or "reset"
     #[arg(long = "scroll", value_enum, default_value_t = ScrollMode::Wrapping)]
     pub scroll_mode: ScrollMode,
+    /// Cycles to hold at the start of each loop in wrapping mode
+    #[arg(long = "wrap-start-hold", default_value_t = 0)]
+    pub wrap_start_hold: usize,
+    /// Spaces between the end of the text and its start in wrapping mode (at least 1)
+    #[arg(long = "wrap-gap", default_value_t = WRAP_GAP)]
+    pub wrap_gap: usize,
+    /// Cycles to hold at the start and end in reset mode
+    #[arg(long = "reset-hold", default_value_t = RESET_HOLD)]
+    pub reset_hold: usize,
+    /// Characters the text moves per scroll frame
+    #[arg(long = "scroll-step", default_value_t = 1)]
+    pub scroll_step: usize,
+    /// Spaces added before and after the text in reset mode
+    #[arg(long = "scroll-padding", default_value_t = 0)]
+    pub scroll_padding: usize,
     /// Metadata format string
-    #[arg(long = "format", default_value = "{title} - {artist}")]
+    #[arg(long = "format", env = "SCROLLMPRIS_FORMAT", default_value = "{title} - {artist}")]
     pub format: String,
+    /// Metadata format string while paused (defaults to --format)
+    #[arg(long = "format-paused")]
+    pub format_paused: Option<String>,
+    /// Metadata format for the last played track once the player stops (default: blank)
+    #[arg(long = "format-stopped")]
+    pub format_stopped: Option<String>,
+    /// Look up the next track for {next_title} (players with a TrackList only)
+    #[arg(long = "show-next", default_value_t = false, action = clap::ArgAction::SetTrue)]
+    pub show_next: bool,
+    /// Follow a playing player rather than a paused one, even if the paused one is more recent
+    #[arg(long = "prefer-playing", default_value_t = false, action = clap::ArgAction::SetTrue)]
+    pub prefer_playing: bool,
+    /// Without playerctld, re-list players this often to notice new or closed ones (0 = off)
+    #[arg(long = "poll-interval", default_value_t = 3)]
+    pub poll_interval: u64,
+    /// Use the player's Identity (e.g. "Mozilla Firefox") for icons and {player}
+    #[arg(long = "use-identity", default_value_t = false, action = clap::ArgAction::SetTrue)]
+    pub use_identity: bool,
+    /// Match icons and fill {app} with the player's DesktopEntry (e.g. for Flatpak players)
+    #[arg(long = "use-desktop-entry", default_value_t = false, action = clap::ArgAction::SetTrue)]
+    pub use_desktop_entry: bool,
+    /// Strip noise like "(Remastered 2011)" or "feat. X" from titles
+    #[arg(long = "clean-title", default_value_t = false, action = clap::ArgAction::SetTrue)]
+    pub clean_title: bool,
+    /// Extra regex stripped from titles (repeatable)
+    #[arg(long = "clean-title-pattern", value_parser = Regex::new, action = clap::ArgAction::Append)]
+    pub clean_title_patterns: Vec<Regex>,
+    /// Fields to show as the title when a track has none, in order: album, artist (comma-separated)
+    #[arg(long = "title-fallback", value_delimiter = ',', value_parser = ["album", "artist"])]
+    pub title_fallback: Vec<String>,
+    /// Literal text removed from the start of titles (repeatable)
+    #[arg(long = "strip-prefix", action = clap::ArgAction::Append)]
+    pub strip_prefix: Vec<String>,
+    /// Literal text removed from the end of titles, like " - YouTube" (repeatable)
+    #[arg(long = "strip-suffix", action = clap::ArgAction::Append)]
+    pub strip_suffix: Vec<String>,
     /// Metadata format string for tooltip
     #[arg(long = "tooltip-format", default_value = "{title} - {artist} | {album}")]
     pub tooltip_format: String,
+    /// Show a field only once when it repeats another one already shown (e.g. title = artist)
+    #[arg(long = "dedupe-fields", default_value_t = false, action = clap::ArgAction::SetTrue)]
+    pub dedupe_fields: bool,
+    /// Add an "elapsed / length" line to the tooltip, kept current while playing
+    #[arg(long = "tooltip-position", default_value_t = false, action = clap::ArgAction::SetTrue)]
+    pub tooltip_position: bool,
     /// Custom icons
     #[arg(
         long = "icon-format",
-        default_value = "{\"spotify\": \"\", \"vlc\": \"󰕼\", \"edge\": \"󰇩\", \"firefox\": \"󰈹\", \"mpv\": \"\", \"chrome\": \"\", \"telegramdesktop\": \"\", \"tauon\": \"\", \"404\": \"\"}"
+        default_value = "{\"spotify\": \"\", \"vlc\": \"󰕼\", \"edge\": \"󰇩\", \"firefox\": \"󰈹\", \"mpv\": \"\", \"chrome\": \"\", \"telegramdesktop\": \"\", \"tauon\": \"\", \"404\": \"\", \"live\": \"\"}"
     )]
     icon_format_json: String,
+    /// Add or replace one --icon-format entry, as keyword=glyph (repeatable)
+    #[arg(long = "icon", action = clap::ArgAction::Append)]
+    icons: Vec<String>,
+    /// Friendly labels for {player} as JSON, keyed by service or base name
+    #[arg(long = "player-names", default_value = "{}")]
+    player_names_json: String,
     /// Show track time info
     #[arg(short = 'p', long = "position", default_value_t = false, action = clap::ArgAction::SetTrue)]
     pub position_enabled: bool,
+    /// Hard cap in display columns on the whole output text (icon, text and position), cut with "…"
+    #[arg(long = "max-length")]
+    pub max_length: Option<usize>,
+    /// Pad the output text with spaces to at least this display width
+    #[arg(long = "min-width")]
+    pub min_width: Option<usize>,
+    /// Alignment of text shorter than --min-width: left, center or right
+    #[arg(long = "align", value_enum, default_value_t = Align::Left)]
+    pub align: Align,
+    /// Separator between the icon and the text
+    #[arg(long = "icon-separator", default_value = " ")]
+    pub icon_separator: String,
+    /// Put the icon before (left) or after (right) the text and position
+    #[arg(long = "icon-position", value_enum, default_value_t = IconPosition::Left)]
+    pub icon_position: IconPosition,
     /// Disable icon in output
     #[arg(long = "no-icon", default_value_t = false, action = clap::ArgAction::SetTrue)]
     pub no_icon: bool,
+    /// Glyph shown before the text when a browser tab is playing
+    #[arg(long = "browser-label")]
+    pub browser_label: Option<String>,
+    /// With --browser-label, move a trailing " - Site" from the title into {player}
+    #[arg(long = "browser-site", default_value_t = false, action = clap::ArgAction::SetTrue)]
+    pub browser_site: bool,
+    /// Scroll only this placeholder (e.g. "title") and keep the rest of the format static
+    #[arg(long = "scroll-field")]
+    pub scroll_field: Option<String>,
+    /// Drop fields from the text (see --compact-order) before resorting to scrolling
+    #[arg(long = "compact", default_value_t = false, action = clap::ArgAction::SetTrue)]
+    pub compact: bool,
+    /// Placeholders --compact drops, first to last (comma-separated)
+    #[arg(long = "compact-order", value_delimiter = ',', default_value = "album,artist")]
+    pub compact_order: Vec<String>,
+    /// Formats to rotate through instead of --format (repeatable)
+    #[arg(long = "cycle-formats", action = clap::ArgAction::Append)]
+    pub cycle_formats: Vec<String>,
+    /// Seconds each of --cycle-formats is shown
+    #[arg(long = "cycle-interval", default_value_t = 5)]
+    pub cycle_interval: u64,
+    /// Keep control characters in metadata instead of stripping them
+    #[arg(long = "no-sanitize", default_value_t = false, action = clap::ArgAction::SetTrue)]
+    pub no_sanitize: bool,
+    /// Also strip zero-width and other invisible format characters (joiners inside emoji are kept)
+    #[arg(long = "strip-invisible", default_value_t = false, action = clap::ArgAction::SetTrue)]
+    pub strip_invisible: bool,
+    /// Letter case for the formatted text: none, upper, lower or title
+    #[arg(long = "case", value_enum, default_value_t = TextCase::None)]
+    pub case: TextCase,
     /// Position style: "increasing" or "remaining"
     #[arg(long = "position-mode", default_value = "increasing")]
     pub position_mode: PositionMode,
-    /// Freeze scrolling and reset text when paused
+    /// When to show the position: always, paused or playing
+    #[arg(long = "position-when", value_enum, default_value_t = PositionWhen::Always)]
+    pub position_when: PositionWhen,
+    /// Separator between the text and the position, left out when either is empty
+    #[arg(long = "position-delimiter", default_value = " ")]
+    pub position_delimiter: String,
+    /// Cells in the --position-mode fine-bar progress bar
+    #[arg(long = "bar-width", default_value_t = 10)]
+    pub bar_width: usize,
+    /// Shown in place of the position for streams without a length
+    #[arg(long = "live-text", default_value = "LIVE")]
+    pub live_text: String,
+    /// Include a "percentage" field with track progress (also on with --position)
+    #[arg(long = "percentage", default_value_t = false, action = clap::ArgAction::SetTrue)]
+    pub percentage: bool,
+    /// Position resolution: "s" or "ms" (adds milliseconds to the default template)
+    #[arg(long = "position-precision", value_enum, default_value_t = PositionPrecision::S)]
+    pub position_precision: PositionPrecision,
+    /// Position template: %H/%M/%S zero-padded, %h/%m/%s unpadded, %f milliseconds (default: mm:ss or hh:mm:ss)
+    #[arg(long = "position-format")]
+    pub position_format: Option<String>,
+    /// Don't zero-pad the hours of the default position template (5:44:21 instead of 05:44:21)
+    #[arg(long = "position-no-pad-hours", default_value_t = false, action = clap::ArgAction::SetTrue)]
+    pub position_no_pad_hours: bool,
+    /// Color the position when fewer than this many seconds remain (needs --markup)
+    #[arg(long = "position-warn-secs")]
+    pub position_warn_secs: Option<u64>,
+    /// Color used by --position-warn-secs
+    #[arg(long = "position-warn-color", default_value = "#ff5555")]
+    pub position_warn_color: String,
+    /// Emit Pango markup (metadata is escaped); use with "escape": false in Waybar
+    #[arg(long = "markup", default_value_t = false, action = clap::ArgAction::SetTrue)]
+    pub markup: bool,
+    /// Freeze scrolling when paused (see --freeze-mode)
     #[arg(long = "freeze", default_value_t = false, action = clap::ArgAction::SetTrue)]
     pub freeze_on_pause: bool,
+    /// What --freeze shows while paused: the frame it stopped on or the start of the text
+    #[arg(long = "freeze-mode", value_enum, default_value_t = FreezeMode::InPlace)]
+    pub freeze_mode: FreezeMode,
     /// Delay in milliseconds (from speed)
     #[arg(skip)]
     pub delay: u64,
+    /// Glyph shown while playing (overrides the "play" icon key)
+    #[arg(long = "play-icon")]
+    pub play_icon: Option<String>,
+    /// Glyph shown while paused (overrides the "pause" icon key)
+    #[arg(long = "pause-icon")]
+    pub pause_icon: Option<String>,
     /// Disable status icon
     #[arg(long = "no-status-icon", default_value_t = false, action = clap::ArgAction::SetTrue)]
     pub no_status_icon: bool,
+    /// Show no service icon for players without an --icon-format entry, instead of the "404" one
+    #[arg(long = "no-unknown-icon", default_value_t = false, action = clap::ArgAction::SetTrue)]
+    pub no_unknown_icon: bool,
+    /// Print nothing while stopped instead of an empty line, so the module is hidden
+    #[arg(long = "hide-when-stopped", default_value_t = false, action = clap::ArgAction::SetTrue)]
+    pub hide_when_stopped: bool,
+    /// Keep the scroll position across restarts while the same track plays
+    #[arg(long = "persist-scroll", default_value_t = false, action = clap::ArgAction::SetTrue)]
+    pub persist_scroll: bool,
+    /// Show the stopped output for a player paused at 0 that hasn't played yet
+    #[arg(long = "paused-zero-as-stopped", default_value_t = false, action = clap::ArgAction::SetTrue)]
+    pub paused_zero_as_stopped: bool,
+    /// Show the stopped output after this many seconds paused (0 = never)
+    #[arg(long = "pause-timeout", default_value_t = 0)]
+    pub pause_timeout: u64,
+    /// Add a "track-changed" class for a second after the track changes
+    #[arg(long = "flash-on-change", default_value_t = false, action = clap::ArgAction::SetTrue)]
+    pub flash_on_change: bool,
+    /// Show the whole text, unscrolled, for this many seconds after the track changes (0 = off)
+    #[arg(long = "peek-on-change", default_value_t = 0)]
+    pub peek_on_change: u64,
+    /// Shorten the text over this many seconds before the track ends (0 = off)
+    #[arg(long = "fade-out", default_value_t = 0)]
+    pub fade_out: u64,
+    /// Blank the output while the player reports itself fullscreen
+    #[arg(long = "inhibit-when-fullscreen", default_value_t = false, action = clap::ArgAction::SetTrue)]
+    pub inhibit_when_fullscreen: bool,
+    /// Only redraw on D-Bus events (no scrolling, position won't animate)
+    #[arg(long = "no-tick", default_value_t = false, action = clap::ArgAction::SetTrue)]
+    pub no_tick: bool,
+    /// Redraw immediately on SIGRTMIN+N, e.g. `pkill -RTMIN+8 ScrollMPRIS`
+    #[arg(long = "redraw-signal")]
+    pub redraw_signal: Option<i32>,
+    /// Output format: "waybar"/"json" (JSON object) or "plain" (text only)
+    #[arg(long = "output-format", value_enum, default_value_t = OutputFormat::Waybar)]
+    pub output_format: OutputFormat,
+    /// Minimum time in milliseconds between event-driven redraws (0 = off)
+    #[arg(long = "debounce", default_value_t = 0)]
+    pub debounce: u64,
+    /// Milliseconds a pause must last before it is shown (hides buffering blips)
+    #[arg(long = "status-debounce", default_value_t = 0)]
+    pub status_debounce: u64,
+    /// Seek the current player by signed seconds (e.g. +10, -10) and exit
+    #[arg(long = "seek", allow_hyphen_values = true)]
+    pub seek: Option<i64>,
+    /// Re-read the player directly after this many seconds without D-Bus events while playing
+    #[arg(long = "heartbeat", default_value_t = 0)]
+    pub heartbeat: u64,
+    /// Always emit "class" as a list, adding "live" for streams
+    #[arg(long = "json-class-array", default_value_t = false, action = clap::ArgAction::SetTrue)]
+    pub json_class_array: bool,
+    /// Write status lines to this file or FIFO instead of stdout
+    #[arg(long = "output")]
+    pub output: Option<String>,
+    /// Keep playback metrics in this file, in Prometheus text format
+    #[arg(long = "metrics-file")]
+    pub metrics_file: Option<String>,
+    /// Print everything this player reports as metadata (as JSON), then exit
+    #[arg(long = "query")]
+    pub query: Option<String>,
+    /// Check the session bus, playerctld and the players, show which would be followed, then exit
+    #[arg(long = "diagnose", default_value_t = false, action = clap::ArgAction::SetTrue)]
+    pub diagnose: bool,
+    /// Serve the current player state as JSON on this Unix socket, and take commands on it
+    #[arg(long = "socket")]
+    pub socket: Option<String>,
     #[arg(skip)]
     pub icon_format: HashMap<String, String>,
+    #[arg(skip)]
+    pub icon_colors: HashMap<String, String>, // Icon key -> color from --icon-format
+    #[arg(skip)]
+    pub player_names: HashMap<String, String>,
 }
 
 impl Config {
-    /// Parse arguments and compute derived fields.
+    /// Parse arguments and the --config file, and compute derived fields.
     pub fn parse() -> Self {
-        let mut config = <Self as Parser>::parse();
-        // Calculate delay from speed (speed 0 = 1000ms, speed 100 = 100ms)
-        config.delay = (1000u64)
-            .saturating_sub((config.speed as u64).saturating_mul(9))
-            .max(100);
+        let config = <Self as Parser>::parse();
+        let config = match &config.config_file {
+            Some(_) => config.reload(),
+            None => config.finish(),
+        };
+        config.unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        })
+    }
+
+    /// Re-read the --config file and parse it under the command-line
+    /// arguments, which still take precedence.
+    pub fn reload(&self) -> Result<Self, String> {
+        let mut args = vec![std::env::args().next().unwrap_or_default()];
+        if let Some(path) = &self.config_file {
+            let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+            args.extend(config_file_args(&text).map_err(|e| format!("{}: {}", path, e))?);
+        }
+        args.extend(std::env::args().skip(1));
+        <Self as Parser>::try_parse_from(args)
+            .map_err(|e| e.to_string())?
+            .finish()
+    }
+
+    fn finish(self) -> Result<Self, String> {
+        let mut config = self;
+        // Only the chosen field scrolls there, so there is nothing to compact.
+        if config.compact && config.scroll_field.is_some() {
+            return Err("--compact can't be used with --scroll-field".to_string());
+        }
+        config.delay = speed_to_delay(config.speed);
+        // A missing or unreadable file only costs its entries
+        if let Some(path) = &config.blocked_file {
+            match std::fs::read_to_string(path) {
+                Ok(text) => config.blocked.extend(blocked_file_entries(&text)),
+                Err(e) => eprintln!("Ignoring --blocked-file {:?}: {}", path, e),
+            }
+        }
         // Normalize blocked list
         config.blocked = config
             .blocked
@@ -82,7 +428,170 @@ impl Config {
             .map(|s| s.trim().to_lowercase())
             .filter(|s| !s.is_empty())
             .collect();
-        config.icon_format = serde_json::from_str(&config.icon_format_json).unwrap();
-        config
+        if let Some(template) = &config.position_format {
+            for token in crate::utils::unknown_position_tokens(template) {
+                eprintln!("Unknown token {:?} in --position-format {:?}", token, template);
+            }
+        }
+        // Bad patterns are reported and skipped rather than aborting
+        config.blocked_patterns = config
+            .blocked_regex
+            .iter()
+            .filter_map(|pattern| match Regex::new(pattern) {
+                Ok(re) => Some(re),
+                Err(e) => {
+                    eprintln!("Ignoring invalid --blocked-regex {:?}: {}", pattern, e);
+                    None
+                }
+            })
+            .collect();
+        let icons: HashMap<String, IconEntry> = serde_json::from_str(&config.icon_format_json)
+            .map_err(|e| format!("Invalid --icon-format: {}", e))?;
+        for (key, entry) in icons {
+            match entry {
+                IconEntry::Glyph(glyph) => config.icon_format.insert(key, glyph),
+                IconEntry::Colored { glyph, color } => {
+                    config.icon_colors.insert(key.clone(), color);
+                    config.icon_format.insert(key, glyph)
+                }
+            };
+        }
+        for entry in &config.icons {
+            let (keyword, icon) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid --icon {:?}: expected keyword=glyph", entry))?;
+            config.icon_format.insert(keyword.trim().to_lowercase(), icon.to_string());
+        }
+        if let Some(icon) = &config.play_icon {
+            config.icon_format.insert("play".to_string(), icon.clone());
+        }
+        if let Some(icon) = &config.pause_icon {
+            config.icon_format.insert("pause".to_string(), icon.clone());
+        }
+        if config.no_unknown_icon {
+            config.icon_format.remove("404");
+        }
+        let player_names: HashMap<String, String> = serde_json::from_str(&config.player_names_json)
+            .map_err(|e| format!("Invalid --player-names: {}", e))?;
+        config.player_names = player_names
+            .into_iter()
+            .map(|(key, label)| (key.to_lowercase(), label))
+            .collect();
+        Ok(config)
+    }
+
+    /// Parse `args` the way `parse` does, without the --config file.
+    #[cfg(test)]
+    pub fn from_args(args: &[&str]) -> Self {
+        <Self as Parser>::try_parse_from(std::iter::once("ScrollMPRIS").chain(args.iter().copied()))
+            .unwrap()
+            .finish()
+            .unwrap()
+    }
+
+    /// Players to skip, from --blocked and --blocked-regex.
+    pub fn block_list(&self) -> BlockList {
+        BlockList {
+            substrings: self.blocked.clone(),
+            patterns: self.blocked_patterns.clone(),
+            exact: self.blocked_exact,
+        }
+    }
+}
+
+/// Scroll delay in ms for a --speed of 0-100: linear from 1000ms at 0 down to
+/// 100ms at 100, 9ms per step.
+fn speed_to_delay(speed: u32) -> u64 {
+    1000 - 9 * u64::from(speed.min(100))
+}
+
+/// Entries of a --blocked-file: one per line, skipping blank and `#` lines.
+fn blocked_file_entries(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+}
+
+/// Turn a TOML table into command-line arguments: `key = value` becomes
+/// `--key value`, `key = true` a bare `--key`, and an array a repeated option.
+fn config_file_args(text: &str) -> Result<Vec<String>, String> {
+    let table: toml::Table = text.parse().map_err(|e: toml::de::Error| e.message().to_string())?;
+    let mut args = Vec::new();
+    for (key, value) in table {
+        let flag = format!("--{}", key);
+        let values = match value {
+            toml::Value::Array(items) => items,
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                toml::Value::Boolean(true) => args.push(flag.clone()),
+                toml::Value::Boolean(false) => {}
+                toml::Value::String(s) => args.extend([flag.clone(), s]),
+                toml::Value::Integer(n) => args.extend([flag.clone(), n.to_string()]),
+                toml::Value::Float(n) => args.extend([flag.clone(), n.to_string()]),
+                other => return Err(format!("unsupported value for {:?}: {}", key, other)),
+            }
+        }
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::{Config, blocked_file_entries, config_file_args, speed_to_delay};
+
+    #[test]
+    fn speed_endpoints_map_to_delay_range() {
+        assert_eq!(speed_to_delay(0), 1000);
+        assert_eq!(speed_to_delay(50), 550);
+        assert_eq!(speed_to_delay(100), 100);
+    }
+
+    #[test]
+    fn speed_above_100_is_rejected() {
+        assert!(Config::try_parse_from(["ScrollMPRIS", "--speed", "100"]).is_ok());
+        assert!(Config::try_parse_from(["ScrollMPRIS", "--speed", "101"]).is_err());
+    }
+
+    #[test]
+    fn blocked_file_merges_with_blocked() {
+        let entries: Vec<String> = blocked_file_entries("# browsers\nFirefox\n\n  chromium  \n").collect();
+        assert_eq!(entries, ["Firefox", "chromium"]);
+
+        let path = std::env::temp_dir().join(format!("scrollmpris-blocked-{}", std::process::id()));
+        std::fs::write(&path, "# browsers\nFirefox\n").unwrap();
+        let args = ["ScrollMPRIS", "-b", "mpv", "--blocked-file", path.to_str().unwrap()];
+        let config = Config::try_parse_from(args).unwrap().finish().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(config.blocked, ["mpv", "firefox"]);
+
+        // A missing file is reported, not fatal.
+        let args = ["ScrollMPRIS", "-b", "mpv", "--blocked-file", "/nonexistent/blocked"];
+        assert_eq!(Config::try_parse_from(args).unwrap().finish().unwrap().blocked, ["mpv"]);
+    }
+
+    #[test]
+    fn config_file_flags_and_arrays_become_args() {
+        let text = "position = true\nmarkup = false\nwidth = 30\nblocked-regex = [\"chromium\", \"brave\"]\n";
+        let args = config_file_args(text).unwrap();
+        // Keys come out sorted; `false` leaves the flag off.
+        assert_eq!(args, ["--blocked-regex", "chromium", "--blocked-regex", "brave", "--position", "--width", "30"]);
+    }
+
+    #[test]
+    fn config_file_rejects_tables() {
+        let err = config_file_args("[icons]\nmpv = \"x\"\n").unwrap_err();
+        assert!(err.contains("icons"), "{}", err);
+    }
+
+    #[test]
+    fn compact_is_rejected_with_scroll_field() {
+        let args = ["ScrollMPRIS", "--compact", "--scroll-field", "title"];
+        assert!(Config::try_parse_from(args).unwrap().finish().is_err());
+        assert!(Config::try_parse_from(["ScrollMPRIS", "--compact"]).unwrap().finish().is_ok());
     }
 }
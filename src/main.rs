@@ -1,30 +1,93 @@
 use std::fs;
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::Result;
-use config::Config;
-use mpris::events::MprisEventHandler;
-use player::PlayerState;
-use scroll::ScrollState;
+use config::{Config, PositionMode, PositionPrecision};
+use mpris::backend::{DbusBackend, MprisBackend};
+use mpris::connection::{MprisError, is_blocked, selectable};
+use mpris::events::{HandlerOptions, MprisEventHandler};
+use mpris::metadata::{get_metadata, get_playback_status};
+use output::OutputSink;
+use scroll::{SavedScroll, adaptive_delay};
+use state::AppState;
+use tokio::signal::unix::{SignalKind, signal};
 use tokio::sync::mpsc;
 
 mod config;
+mod metrics;
 mod mpris;
+mod output;
 mod player;
 mod scroll;
+mod socket;
+mod state;
 mod utils;
 
-use utils::print_status;
+use utils::{render_status, runtime_dir, ticks};
+
+/// Window in which events arriving together are rendered once.
+const EVENT_COALESCE: Duration = Duration::from_millis(50);
+/// Longest time between position refreshes while playing.
+const POSITION_TICK: Duration = Duration::from_secs(1);
+/// Position refresh interval with `--position-precision ms`.
+const POSITION_TICK_MS: Duration = Duration::from_millis(100);
+/// Margin past a second boundary, so a position refresh lands just after it.
+const POSITION_TICK_SLACK: Duration = Duration::from_millis(10);
+/// Scroll position left for the next run by --persist-scroll, in the runtime dir.
+const SCROLL_STATE_FILE: &str = "scroll.json";
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let config = Arc::new(Config::parse());
-    let scroll_state = Arc::new(Mutex::new(ScrollState::new()));
-    let last_output = Arc::new(Mutex::new(String::new()));
-    let player_state = Arc::new(Mutex::new(PlayerState::default()));
+
+    // One-shot commands
+    if let Some(offset) = config.seek {
+        mpris::control::seek(offset, &config.block_list()).await?;
+        return Ok(());
+    }
+    if let Some(name) = &config.query {
+        match mpris::metadata::query_metadata(name).await? {
+            Ok(metadata) => println!("{}", serde_json::to_string_pretty(&metadata)?),
+            Err(services) => {
+                eprintln!("No player {:?}. Active players:", name);
+                for service in services {
+                    eprintln!("  {}", service);
+                }
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    if config.diagnose {
+        diagnose(&config).await;
+        return Ok(());
+    }
+
+    let app = Arc::new(Mutex::new(AppState::new()));
+    let sink = Arc::new(Mutex::new(OutputSink::new(config.output.as_deref())));
+    // Rendering reads the latest config, so a SIGHUP reload applies live.
+    let shared_config = Arc::new(RwLock::new(config.clone()));
     let (tx, mut rx) = mpsc::channel(8);
-    let block_list = config.blocked.clone();
+    let block_list = config.block_list();
+    let last_event = Arc::new(Mutex::new(Instant::now()));
+
+    // Redraw on SIGRTMIN+N, writing the line again even if it hasn't changed
+    if let Some(offset) = config.redraw_signal {
+        let signum = libc::SIGRTMIN() + offset;
+        if offset < 0 || signum > libc::SIGRTMAX() {
+            anyhow::bail!("--redraw-signal must be between 0 and {}", libc::SIGRTMAX() - libc::SIGRTMIN());
+        }
+        let mut redraw = signal(SignalKind::from_raw(signum))?;
+        let sink = sink.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            while redraw.recv().await.is_some() {
+                sink.lock().unwrap().last_line.clear();
+                let _ = tx.try_send(());
+            }
+        });
+    }
 
     // Write PID
     let timestamp = SystemTime::now()
@@ -33,32 +96,126 @@ async fn main() -> Result<()> {
         .as_secs();
 
     let pid = std::process::id();
-    let filename = format!("/tmp/scrollbarmpris/{}.pid", timestamp);
-    fs::create_dir_all("/tmp/scrollbarmpris").expect("Failed to create directory at /tmp");
-    fs::write(&filename, pid.to_string()).unwrap();
+    let dir = runtime_dir().expect("Failed to create runtime directory");
+    let pid_file = dir.join(format!("{}.pid", timestamp));
+    fs::write(&pid_file, pid.to_string()).unwrap();
+
+    // Keep the scroll position across restarts (e.g. a Waybar reload): save
+    // it when terminated and resume it if the same track is still playing.
+    let scroll_file = config.persist_scroll.then(|| dir.join(SCROLL_STATE_FILE));
+    if let Some(path) = &scroll_file {
+        app.lock().unwrap().scroll.saved = SavedScroll::take(path);
+    }
+
+    // Shut down on SIGTERM/SIGINT, leaving no files behind but the saved scroll position
+    {
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let app = app.clone();
+        let socket = config.socket.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = interrupt.recv() => {}
+            }
+            if let Some(path) = &scroll_file {
+                let saved = {
+                    let app = app.lock().unwrap();
+                    // Nothing to resume without a track.
+                    (!app.player.title.is_empty()).then(|| app.scroll.save(app.player.track_key()))
+                };
+                if let Some(saved) = saved
+                    && let Err(e) = saved.write(path)
+                {
+                    eprintln!("Failed to save the scroll position: {}", e);
+                }
+            }
+            if let Some(path) = &socket {
+                let _ = fs::remove_file(path);
+            }
+            let _ = fs::remove_file(&pid_file);
+            std::process::exit(0);
+        });
+    }
 
     // Spawn MPRIS event handler
     {
-        let player_state1 = player_state.clone();
+        let app1 = app.clone();
         let tx1 = tx.clone();
-        let player_state2 = player_state.clone();
+        let app2 = app.clone();
         let tx2 = tx.clone();
+        let app3 = app.clone();
+        let tx3 = tx.clone();
+        let app4 = app.clone();
+        let tx4 = tx.clone();
         let block_list = block_list.clone();
+        let options = HandlerOptions {
+            show_next: config.show_next,
+            identity: config.use_identity,
+            desktop_entry: config.use_desktop_entry,
+            fullscreen: config.inhibit_when_fullscreen,
+            prefer_playing: config.prefer_playing,
+            poll_interval: Duration::from_secs(config.poll_interval),
+        };
+        let last_event = last_event.clone();
+        let shared_config = shared_config.clone();
         tokio::spawn(async move {
             let mut event_handler = MprisEventHandler::new(
                 move |meta, pos, playback_status, service| {
-                    let mut player_state = player_state1.lock().unwrap();
+                    let mut app = app1.lock().unwrap();
+                    let player_state = &mut app.player;
+                    if player_state.has_changed(&meta) {
+                        player_state.track_changed_at = Some(Instant::now());
+                    }
                     player_state.update_from_metadata(&meta);
                     player_state.set_service(&service);
-                    player_state.update_playback_dbus(playback_status.to_string(), pos);
+                    let status_debounce = Duration::from_millis(shared_config.read().unwrap().status_debounce);
+                    if !status_debounce.is_zero() && player_state.playing && playback_status == "Paused" {
+                        // Buffering streams report brief pauses; keep showing
+                        // playback and only apply the pause if it lasts.
+                        let seen = Instant::now();
+                        player_state.pending_pause = Some((seen, pos));
+                        player_state.update_playback_dbus("Playing".to_string(), pos);
+                        let app = app1.clone();
+                        let tx = tx1.clone();
+                        tokio::spawn(async move {
+                            tokio::time::sleep(status_debounce).await;
+                            let mut app = app.lock().unwrap();
+                            let player_state = &mut app.player;
+                            if let Some((pending, pos)) = player_state.pending_pause
+                                && pending == seen
+                            {
+                                player_state.pending_pause = None;
+                                player_state.update_playback_dbus("Paused".to_string(), pos);
+                                let _ = tx.try_send(());
+                            }
+                        });
+                    } else {
+                        player_state.pending_pause = None;
+                        player_state.update_playback_dbus(playback_status.to_string(), pos);
+                    }
                     let _ = tx1.try_send(());
                 },
                 move |_meta, pos, _service| {
-                    let mut player_state = player_state2.lock().unwrap();
-                    player_state.reset_position_cache(pos);
+                    app2.lock().unwrap().player.reset_position_cache(pos);
                     let _ = tx2.try_send(());
                 },
+                move |err| {
+                    app3.lock().unwrap().player.err = Some(err);
+                    let _ = tx3.try_send(());
+                },
+                move |info| {
+                    let mut app = app4.lock().unwrap();
+                    let player_state = &mut app.player;
+                    player_state.fullscreen = info.fullscreen;
+                    player_state.next_title = info.next_title;
+                    player_state.identity = info.identity;
+                    player_state.desktop_entry = info.desktop_entry;
+                    let _ = tx4.try_send(());
+                },
                 block_list,
+                options,
+                last_event,
             )
             .await
             .expect("Failed to create MPRIS event handler");
@@ -66,40 +223,247 @@ async fn main() -> Result<()> {
         });
     }
 
+    // Spawn heartbeat: the handler's player, if playing and silent for a whole
+    // interval, is re-read directly in case the event handler has stalled.
+    if config.heartbeat > 0 {
+        let app = app.clone();
+        let last_event = last_event.clone();
+        let tx = tx.clone();
+        let every = Duration::from_secs(config.heartbeat);
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(every);
+            loop {
+                tick.tick().await;
+                if last_event.lock().unwrap().elapsed() < every {
+                    continue;
+                }
+                let service = {
+                    let app = app.lock().unwrap();
+                    let player_state = &app.player;
+                    match player_state.get_service() {
+                        Some(service) if player_state.playing && !service.is_empty() => service.to_string(),
+                        _ => continue,
+                    }
+                };
+                let state = async {
+                    let meta = get_metadata(&service).await?;
+                    let status = get_playback_status(&service).await?;
+                    Ok::<_, MprisError>((meta, status))
+                }
+                .await;
+                // Wait another whole interval before checking again.
+                *last_event.lock().unwrap() = Instant::now();
+                {
+                    let mut app = app.lock().unwrap();
+                    let player_state = &mut app.player;
+                    match state {
+                        // The handler may have moved on to another player meanwhile.
+                        _ if player_state.get_service() != Some(service.as_str()) => {}
+                        Ok((meta, status)) => {
+                            let track_changed = player_state.has_changed(&meta);
+                            if track_changed {
+                                player_state.update_from_metadata(&meta);
+                            }
+                            // A new track restarts the position estimate from 0.
+                            if track_changed || status != player_state.status {
+                                let position = player_state.estimate_position();
+                                player_state.update_playback_dbus(status, Some(position));
+                            }
+                            player_state.err = None;
+                        }
+                        Err(e) => player_state.err = Some(format!("Heartbeat: {}", e)),
+                    }
+                }
+                let _ = tx.try_send(());
+            }
+        });
+    }
+
     // Spawn status printer
     {
-        let player_state = player_state.clone();
-        let scroll_state = scroll_state.clone();
-        let last_output = last_output.clone();
-        let config = config.clone();
+        let app = app.clone();
+        let sink = sink.clone();
+        let shared_config = shared_config.clone();
+        tokio::spawn(async move {
+            while rx.recv().await.is_some() {
+                // Collapse a burst of events into a single render.
+                tokio::time::sleep(EVENT_COALESCE).await;
+                while rx.try_recv().is_ok() {}
+                loop {
+                    let config = shared_config.read().unwrap().clone();
+                    let debounce = Duration::from_millis(config.debounce);
+                    let (render, line, stats) = {
+                        let mut app = app.lock().unwrap();
+                        let app = &mut *app;
+                        let line = render_status(&config, &mut app.player, &mut app.scroll, true);
+                        (app.next_render(), line, config.metrics_file.as_ref().map(|_| metrics::render(&app.player)))
+                    };
+                    sink.lock().unwrap().write_if_changed(render, line);
+                    if let (Some(path), Some(stats)) = (&config.metrics_file, stats) {
+                        let _ = metrics::write_metrics(path, &stats);
+                    }
+                    if debounce.is_zero() {
+                        break;
+                    }
+                    // Hold the new output for the debounce window, then render
+                    // once more if anything changed in the meantime.
+                    tokio::time::sleep(debounce).await;
+                    let mut pending = false;
+                    while rx.try_recv().is_ok() {
+                        pending = true;
+                    }
+                    if !pending {
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    // Spawn state socket
+    if let Some(path) = config.socket.clone() {
+        let app = app.clone();
+        let shared_config = shared_config.clone();
+        let tx = tx.clone();
         tokio::spawn(async move {
-            while let Some(_) = rx.recv().await {
-                let mut player_state = player_state.lock().unwrap();
-                let mut scroll_state = scroll_state.lock().unwrap();
-                let mut last_output = last_output.lock().unwrap();
-                print_status(
-                    &config,
-                    &mut player_state,
-                    &mut scroll_state,
-                    &mut last_output,
-                );
+            if let Err(e) = socket::serve(&path, app, shared_config, tx).await {
+                eprintln!("Socket error: {}", e);
             }
         });
     }
 
-    // Main loop: periodic update
+    // Reload the config file on SIGHUP; a bad file keeps the current config.
+    // Tasks read the shared config at use time, except for the options set up
+    // once above: player selection, --heartbeat, --output, --socket,
+    // --redraw-signal, --persist-scroll and --no-tick need a restart.
+    if config.config_file.is_some() {
+        let mut hangup = signal(SignalKind::hangup())?;
+        let shared_config = shared_config.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                let current = shared_config.read().unwrap().clone();
+                match current.reload() {
+                    Ok(reloaded) => {
+                        *shared_config.write().unwrap() = Arc::new(reloaded);
+                        let _ = tx.try_send(());
+                    }
+                    Err(e) => eprintln!("Keeping the current config: {}", e),
+                }
+            }
+        });
+    }
+
+    // Pure event mode: output only changes when D-Bus events arrive
+    if config.no_tick {
+        std::future::pending::<()>().await;
+    }
+
+    // Main loop: periodic update. Scrolling advances on its own delay, while
+    // the position is refreshed without moving the text each time the shown
+    // seconds change, so the clock (or countdown) steps evenly.
+    let position_tick = tokio::time::sleep(POSITION_TICK);
+    tokio::pin!(position_tick);
+    let scroll_tick = tokio::time::sleep(Duration::from_millis(config.delay));
+    tokio::pin!(scroll_tick);
+    let mut cycle_every = Duration::from_secs(config.cycle_interval.max(1));
+    let mut format_tick = tokio::time::interval_at(tokio::time::Instant::now() + cycle_every, cycle_every);
     loop {
-        tokio::time::sleep(Duration::from_millis(config.delay)).await;
-        let mut player_state = player_state.lock().unwrap();
-        if player_state.playing {
-            let mut scroll_state = scroll_state.lock().unwrap();
-            let mut last_output = last_output.lock().unwrap();
-            print_status(
-                &config,
-                &mut player_state,
-                &mut scroll_state,
-                &mut last_output,
-            );
+        let config = shared_config.read().unwrap().clone();
+        let cycling = config.cycle_formats.len() > 1;
+        // Restart the cycle when a reload changed --cycle-interval.
+        if Duration::from_secs(config.cycle_interval.max(1)) != cycle_every {
+            cycle_every = Duration::from_secs(config.cycle_interval.max(1));
+            format_tick = tokio::time::interval_at(tokio::time::Instant::now() + cycle_every, cycle_every);
+        }
+        let advance_scroll = tokio::select! {
+            _ = &mut scroll_tick => true,
+            _ = &mut position_tick, if config.position_enabled || config.tooltip_position => false,
+            _ = format_tick.tick(), if cycling => {
+                app.lock().unwrap().scroll.format_index += 1;
+                false
+            }
+        };
+        let (cycle_len, next_position, rendered) = {
+            let mut app = app.lock().unwrap();
+            let app = &mut *app;
+            let player_state = &mut app.player;
+            let rendered = ticks(&config, player_state).then(|| {
+                let advance_scroll = advance_scroll && player_state.playing;
+                let line = render_status(&config, player_state, &mut app.scroll, advance_scroll);
+                (line, config.metrics_file.as_ref().map(|_| metrics::render(player_state)))
+            });
+            let next_position = match config.position_precision {
+                PositionPrecision::S => {
+                    let remaining = config.position_mode == PositionMode::Remaining;
+                    player_state.until_next_second(remaining) + POSITION_TICK_SLACK
+                }
+                PositionPrecision::Ms => POSITION_TICK_MS,
+            };
+            let rendered = rendered.map(|(line, stats)| (app.next_render(), line, stats));
+            (app.scroll.cycle_len, next_position.min(POSITION_TICK), rendered)
+        };
+        if let Some((render, line, stats)) = rendered {
+            sink.lock().unwrap().write_if_changed(render, line);
+            if let (Some(path), Some(stats)) = (&config.metrics_file, stats) {
+                let _ = metrics::write_metrics(path, &stats);
+            }
+        }
+        position_tick.as_mut().reset(tokio::time::Instant::now() + next_position);
+        if advance_scroll {
+            let delay = if config.adaptive_speed {
+                adaptive_delay(config.delay, config.width, cycle_len)
+            } else {
+                config.delay
+            };
+            scroll_tick
+                .as_mut()
+                .reset(tokio::time::Instant::now() + Duration::from_millis(delay));
+        }
+    }
+}
+
+/// Print what ScrollMPRIS sees on the bus, for `--diagnose`.
+async fn diagnose(config: &Config) {
+    let backend = match DbusBackend::shared().await {
+        Ok(backend) => {
+            println!("Session bus: reachable");
+            backend
         }
+        Err(e) => {
+            println!("Session bus: not reachable ({})", e);
+            return;
+        }
+    };
+    let playerctld = backend.playerctld_names().await.ok().flatten();
+    let fallback = playerctld.is_none();
+    match &playerctld {
+        Some(_) => println!("playerctld: running, players ordered by recent activity"),
+        None => println!("playerctld: not running, following the most recently active player"),
+    }
+    let services = match playerctld {
+        Some(names) => Ok(names),
+        None => backend.list_services().await,
+    };
+    let services = match services {
+        Ok(services) => services,
+        Err(e) => {
+            println!("Players: listing failed ({})", e);
+            return;
+        }
+    };
+    let block_list = config.block_list();
+    println!("Players:");
+    if services.is_empty() {
+        println!("  (none)");
+    }
+    for service in &services {
+        let blocked = if is_blocked(service, &block_list) { " (blocked)" } else { "" };
+        println!("  {}{}", service, blocked);
+    }
+    match selectable(services, &block_list).first() {
+        Some(service) if fallback => println!("Selected: {} (until another player sends an update)", service),
+        Some(service) => println!("Selected: {}", service),
+        None => println!("Selected: none, the module stays blank"),
     }
 }
@@ -0,0 +1,32 @@
+//! Playback metrics in the Prometheus text format, for node_exporter's
+//! textfile collector (`--metrics-file`).
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+
+use crate::player::PlayerState;
+
+/// Render the metrics for `player_state`.
+pub fn render(player_state: &PlayerState) -> String {
+    let metrics = [
+        ("scrollmpris_playing", "Whether the followed player is playing (1) or not (0).", if player_state.playing { 1.0 } else { 0.0 }),
+        ("scrollmpris_position_seconds", "Playback position of the current track.", player_state.estimate_position().as_secs_f64()),
+        ("scrollmpris_length_seconds", "Length of the current track, 0 for streams.", player_state.length.unwrap_or_default().as_secs_f64()),
+        ("scrollmpris_track_changes_total", "Track changes seen since startup.", player_state.generation as f64),
+    ];
+    let mut out = String::new();
+    for (name, help, value) in metrics {
+        let kind = if name.ends_with("_total") { "counter" } else { "gauge" };
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value);
+    }
+    out
+}
+
+/// Replace the metrics file at `path`. The text is written to a temporary
+/// file next to it and renamed into place, so a scrape never sees half a file.
+pub fn write_metrics(path: &str, text: &str) -> io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, text)?;
+    fs::rename(&tmp, path)
+}
@@ -0,0 +1,181 @@
+//! The D-Bus queries player discovery and tracking depend on, behind a trait
+//! so the selection and event logic can run against a fake bus in tests.
+
+use dbus::nonblock::stdintf::org_freedesktop_dbus::Properties;
+use dbus::nonblock::{Proxy, SyncConnection};
+use std::sync::Arc;
+use std::time::Duration;
+use crate::mpris::connection::{MPRIS_PREFIX, MprisError, PLAYERCTLD_SERVICE, TIMEOUT, get_dbus_conn};
+use crate::mpris::metadata::{TrackMetadata, extract_metadata, micros_to_duration};
+use crate::mpris::tracklist::get_next_title;
+
+const MPRIS_ROOT_INTERFACE: &str = "org.mpris.MediaPlayer2";
+const MPRIS_PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+
+pub trait MprisBackend {
+    /// Player names as ordered by playerctld, or `None` if it isn't running.
+    async fn playerctld_names(&self) -> Result<Option<Vec<String>>, MprisError>;
+    /// All MPRIS services on the bus, in no particular order.
+    async fn list_services(&self) -> Result<Vec<String>, MprisError>;
+    /// Unique bus name (e.g. `:1.42`) currently owning `service`.
+    async fn name_owner(&self, service: &str) -> Result<String, MprisError>;
+    async fn metadata(&self, service: &str) -> Result<TrackMetadata, MprisError>;
+    async fn position(&self, service: &str) -> Result<Duration, MprisError>;
+    /// "Playing", "Paused" or "Stopped".
+    async fn playback_status(&self, service: &str) -> Result<String, MprisError>;
+    async fn fullscreen(&self, service: &str) -> Result<bool, MprisError>;
+    async fn identity(&self, service: &str) -> Result<String, MprisError>;
+    /// Name of the player's `.desktop` file, without the extension.
+    async fn desktop_entry(&self, service: &str) -> Result<String, MprisError>;
+    /// Title of the track after `track_id`, for players with a TrackList.
+    async fn next_title(&self, service: &str, track_id: &str) -> Option<String>;
+}
+
+/// The real session bus.
+pub struct DbusBackend {
+    conn: Arc<SyncConnection>,
+}
+
+impl DbusBackend {
+    pub fn new(conn: Arc<SyncConnection>) -> Self {
+        Self { conn }
+    }
+
+    /// Backend on the process-wide shared connection.
+    pub async fn shared() -> Result<Self, MprisError> {
+        Ok(Self::new(get_dbus_conn().await?))
+    }
+
+    fn player(&self, service: &str) -> Proxy<'_, Arc<SyncConnection>> {
+        Proxy::new(service.to_string(), "/org/mpris/MediaPlayer2", TIMEOUT, self.conn.clone())
+    }
+
+    fn bus(&self) -> Proxy<'_, Arc<SyncConnection>> {
+        Proxy::new("org.freedesktop.DBus", "/org/freedesktop/DBus", TIMEOUT, self.conn.clone())
+    }
+}
+
+impl MprisBackend for DbusBackend {
+    async fn playerctld_names(&self) -> Result<Option<Vec<String>>, MprisError> {
+        let result = Properties::get(&self.player(PLAYERCTLD_SERVICE), "com.github.altdesktop.playerctld", "PlayerNames").await;
+        Ok(result.ok())
+    }
+
+    async fn list_services(&self) -> Result<Vec<String>, MprisError> {
+        let (names,): (Vec<String>,) = self.bus().method_call("org.freedesktop.DBus", "ListNames", ()).await?;
+        Ok(names
+            .into_iter()
+            .filter(|name| name.starts_with(MPRIS_PREFIX) && name != PLAYERCTLD_SERVICE)
+            .collect())
+    }
+
+    async fn name_owner(&self, service: &str) -> Result<String, MprisError> {
+        let (owner,): (String,) = self.bus().method_call("org.freedesktop.DBus", "GetNameOwner", (service,)).await?;
+        Ok(owner)
+    }
+
+    async fn metadata(&self, service: &str) -> Result<TrackMetadata, MprisError> {
+        let metadata: dbus::arg::PropMap = Properties::get(&self.player(service), MPRIS_PLAYER_INTERFACE, "Metadata").await?;
+        Ok(extract_metadata(&metadata))
+    }
+
+    async fn position(&self, service: &str) -> Result<Duration, MprisError> {
+        let micros: i64 = Properties::get(&self.player(service), MPRIS_PLAYER_INTERFACE, "Position").await?;
+        Ok(micros_to_duration(micros))
+    }
+
+    async fn playback_status(&self, service: &str) -> Result<String, MprisError> {
+        Ok(Properties::get(&self.player(service), MPRIS_PLAYER_INTERFACE, "PlaybackStatus").await?)
+    }
+
+    async fn fullscreen(&self, service: &str) -> Result<bool, MprisError> {
+        Ok(Properties::get(&self.player(service), MPRIS_ROOT_INTERFACE, "Fullscreen").await?)
+    }
+
+    async fn identity(&self, service: &str) -> Result<String, MprisError> {
+        Ok(Properties::get(&self.player(service), MPRIS_ROOT_INTERFACE, "Identity").await?)
+    }
+
+    async fn desktop_entry(&self, service: &str) -> Result<String, MprisError> {
+        Ok(Properties::get(&self.player(service), MPRIS_ROOT_INTERFACE, "DesktopEntry").await?)
+    }
+
+    async fn next_title(&self, service: &str, track_id: &str) -> Option<String> {
+        get_next_title(&self.player(service), track_id).await
+    }
+}
+
+/// In-memory stand-in for the bus, answering from canned player data.
+#[cfg(test)]
+pub mod fake {
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use crate::mpris::connection::MprisError;
+    use crate::mpris::metadata::TrackMetadata;
+    use super::MprisBackend;
+
+    #[derive(Debug, Clone, Default)]
+    pub struct FakePlayer {
+        pub owner: String,
+        pub metadata: Option<TrackMetadata>, // None fails the Metadata read
+        pub position: Option<Duration>,      // None: no Position property
+        pub status: String,
+        pub fullscreen: bool,
+    }
+
+    #[derive(Debug, Default)]
+    pub struct FakeBackend {
+        pub playerctld: Option<Vec<String>>,
+        pub players: HashMap<String, FakePlayer>,
+    }
+
+    impl FakeBackend {
+        fn player(&self, service: &str) -> Result<&FakePlayer, MprisError> {
+            self.players.get(service).ok_or(MprisError::NoPlayer)
+        }
+    }
+
+    impl MprisBackend for FakeBackend {
+        async fn playerctld_names(&self) -> Result<Option<Vec<String>>, MprisError> {
+            Ok(self.playerctld.clone())
+        }
+
+        async fn list_services(&self) -> Result<Vec<String>, MprisError> {
+            let mut names: Vec<String> = self.players.keys().cloned().collect();
+            names.sort();
+            Ok(names)
+        }
+
+        async fn name_owner(&self, service: &str) -> Result<String, MprisError> {
+            Ok(self.player(service)?.owner.clone())
+        }
+
+        async fn metadata(&self, service: &str) -> Result<TrackMetadata, MprisError> {
+            self.player(service)?.metadata.clone().ok_or(MprisError::NoPlayer)
+        }
+
+        async fn position(&self, service: &str) -> Result<Duration, MprisError> {
+            self.player(service)?.position.ok_or(MprisError::NoPlayer)
+        }
+
+        async fn playback_status(&self, service: &str) -> Result<String, MprisError> {
+            Ok(self.player(service)?.status.clone())
+        }
+
+        async fn fullscreen(&self, service: &str) -> Result<bool, MprisError> {
+            Ok(self.player(service)?.fullscreen)
+        }
+
+        async fn identity(&self, _service: &str) -> Result<String, MprisError> {
+            Ok(String::new())
+        }
+
+        async fn desktop_entry(&self, _service: &str) -> Result<String, MprisError> {
+            Ok(String::new())
+        }
+
+        async fn next_title(&self, _service: &str, _track_id: &str) -> Option<String> {
+            None
+        }
+    }
+}
@@ -1,9 +1,11 @@
 //! Minimal D-Bus connection and player discovery for MPRIS.
 
-use dbus::nonblock::{SyncConnection, Proxy};
-use dbus::nonblock::stdintf::org_freedesktop_dbus::Properties;
+use dbus::nonblock::SyncConnection;
+use regex::Regex;
+use std::collections::HashSet;
 use std::sync::Arc;
 use std::time::Duration;
+use crate::mpris::backend::{DbusBackend, MprisBackend};
 
 pub const TIMEOUT: Duration = Duration::from_millis(5000);
 
@@ -13,6 +15,8 @@ pub enum MprisError {
     DBus(#[from] dbus::Error),
     #[error("No connection to D-Bus")]
     NoConnection,
+    #[error("No active MPRIS player")]
+    NoPlayer,
 }
 
 pub async fn get_dbus_conn() -> Result<Arc<SyncConnection>, MprisError> {
@@ -22,23 +26,130 @@ pub async fn get_dbus_conn() -> Result<Arc<SyncConnection>, MprisError> {
     }
     let (resource, conn) = dbus_tokio::connection::new_session_sync()
         .map_err(|_| MprisError::NoConnection)?;
-    tokio::spawn(async move { resource.await });
+    tokio::spawn(resource);
     let _ = ONCE.set(conn.clone());
     Ok(conn)
 }
 
+pub const MPRIS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+pub const PLAYERCTLD_SERVICE: &str = "org.mpris.MediaPlayer2.playerctld";
+
+/// Active players, most recent first if playerctld is running.
 pub async fn get_active_player_names() -> Result<Vec<String>, MprisError> {
-    let conn = get_dbus_conn().await?;
-    let proxy = Proxy::new(
-        "org.mpris.MediaPlayer2.playerctld",
-        "/org/mpris/MediaPlayer2",
-        TIMEOUT,
-        conn,
-    );
-    let result = Properties::get(&proxy, "com.github.altdesktop.playerctld", "PlayerNames").await;
-    Ok(result.unwrap_or_default())
-}
-
-pub fn is_blocked(service: &str, block_list: &[String]) -> bool {
-    block_list.iter().any(|b| service.to_lowercase().contains(b))
+    active_player_names(&DbusBackend::shared().await?).await
+}
+
+async fn active_player_names<B: MprisBackend>(backend: &B) -> Result<Vec<String>, MprisError> {
+    match backend.playerctld_names().await? {
+        Some(names) => Ok(names),
+        None => backend.list_services().await,
+    }
+}
+
+/// Players to skip: lowercase substrings of the service name (or, with
+/// `exact`, whole base names), or regexes matched against the full service name.
+#[derive(Debug, Clone, Default)]
+pub struct BlockList {
+    pub substrings: Vec<String>,
+    pub patterns: Vec<Regex>,
+    pub exact: bool,
+}
+
+/// Lowercase service name without the `org.mpris.MediaPlayer2.` prefix and
+/// `.instanceNNN` suffix (`org.mpris.MediaPlayer2.mpv.instance123` -> `mpv`).
+pub fn base_name(service: &str) -> String {
+    let service = service.to_lowercase();
+    let base = service
+        .strip_prefix("org.mpris.mediaplayer2.")
+        .unwrap_or(&service);
+    base.split(".instance").next().unwrap_or(base).to_string()
+}
+
+pub fn is_blocked(service: &str, block_list: &BlockList) -> bool {
+    let lower = service.to_lowercase();
+    let base = base_name(service);
+    block_list
+        .substrings
+        .iter()
+        .any(|b| if block_list.exact { base == *b } else { lower.contains(b) })
+        || block_list.patterns.iter().any(|re| re.is_match(service))
+}
+
+/// First active player that isn't blocked.
+pub async fn get_current_player(block_list: &BlockList) -> Result<Option<String>, MprisError> {
+    current_player(&DbusBackend::shared().await?, block_list).await
+}
+
+async fn current_player<B: MprisBackend>(backend: &B, block_list: &BlockList) -> Result<Option<String>, MprisError> {
+    let names = active_player_names(backend).await?;
+    Ok(selectable(names, block_list).into_iter().next())
+}
+
+/// The players in `names` that may be followed, in order: without blocked
+/// services, repeated names or playerctld itself, which only mirrors another
+/// player.
+pub fn selectable(names: Vec<String>, block_list: &BlockList) -> Vec<String> {
+    let mut seen = HashSet::new();
+    names
+        .into_iter()
+        .filter(|s| s != PLAYERCTLD_SERVICE && !is_blocked(s, block_list) && seen.insert(s.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+
+    use super::{BlockList, PLAYERCTLD_SERVICE, current_player, is_blocked, selectable};
+    use crate::mpris::backend::fake::{FakeBackend, FakePlayer};
+
+    #[tokio::test]
+    async fn current_player_skips_blocked_services() {
+        let backend = FakeBackend {
+            playerctld: Some(vec![
+                "org.mpris.MediaPlayer2.chromium.instance42".to_string(),
+                "org.mpris.MediaPlayer2.brave".to_string(),
+                "org.mpris.MediaPlayer2.mpv".to_string(),
+            ]),
+            ..Default::default()
+        };
+        let block_list = BlockList {
+            substrings: vec!["brave".to_string()],
+            patterns: vec![Regex::new(r"chromium\.instance\d+$").unwrap()],
+            exact: false,
+        };
+        assert_eq!(current_player(&backend, &block_list).await.unwrap().as_deref(), Some("org.mpris.MediaPlayer2.mpv"));
+    }
+
+    #[tokio::test]
+    async fn current_player_without_playerctld_lists_the_bus() {
+        let backend = FakeBackend {
+            playerctld: None,
+            players: [("org.mpris.MediaPlayer2.vlc".to_string(), FakePlayer::default())].into(),
+        };
+        assert_eq!(
+            current_player(&backend, &BlockList::default()).await.unwrap().as_deref(),
+            Some("org.mpris.MediaPlayer2.vlc")
+        );
+        let block_list = BlockList { substrings: vec!["vlc".to_string()], ..Default::default() };
+        assert_eq!(current_player(&backend, &block_list).await.unwrap(), None);
+    }
+
+    #[test]
+    fn exact_blocking_matches_whole_base_names() {
+        let substring = BlockList { substrings: vec!["chrome".to_string()], ..Default::default() };
+        let exact = BlockList { exact: true, ..substring.clone() };
+        let chromecast = "org.mpris.MediaPlayer2.chromecast";
+        let chrome = "org.mpris.MediaPlayer2.chrome.instance1234";
+        assert!(is_blocked(chromecast, &substring));
+        assert!(!is_blocked(chromecast, &exact));
+        assert!(is_blocked(chrome, &exact));
+    }
+
+    #[test]
+    fn selectable_drops_playerctld_and_repeats() {
+        let names = [PLAYERCTLD_SERVICE, "org.mpris.MediaPlayer2.mpv", "org.mpris.MediaPlayer2.mpv", "org.mpris.MediaPlayer2.spotify"];
+        let names = names.map(str::to_string).to_vec();
+        assert_eq!(selectable(names, &BlockList::default()), ["org.mpris.MediaPlayer2.mpv", "org.mpris.MediaPlayer2.spotify"]);
+    }
 }
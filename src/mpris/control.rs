@@ -0,0 +1,24 @@
+//! One-shot player control commands for MPRIS.
+
+use dbus::nonblock::Proxy;
+use crate::mpris::connection::{BlockList, get_current_player, get_dbus_conn, TIMEOUT, MprisError};
+
+const MPRIS_PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+
+/// Seek the current non-blocked player by `offset` seconds.
+pub async fn seek(offset: i64, block_list: &BlockList) -> Result<(), MprisError> {
+    let service = get_current_player(block_list).await?.ok_or(MprisError::NoPlayer)?;
+    let conn = get_dbus_conn().await?;
+    let proxy = Proxy::new(service, "/org/mpris/MediaPlayer2", TIMEOUT, conn);
+    let offset_us = offset.saturating_mul(1_000_000);
+    proxy.method_call::<(), _, _, _>(MPRIS_PLAYER_INTERFACE, "Seek", (offset_us,)).await?;
+    Ok(())
+}
+
+/// Call a no-argument Player `method` (e.g. `Next`) on `service`.
+pub async fn call(service: String, method: &str) -> Result<(), MprisError> {
+    let conn = get_dbus_conn().await?;
+    let proxy = Proxy::new(service, "/org/mpris/MediaPlayer2", TIMEOUT, conn);
+    proxy.method_call::<(), _, _, _>(MPRIS_PLAYER_INTERFACE, method, ()).await?;
+    Ok(())
+}
@@ -1,46 +1,91 @@
 //! Event watching and event handler registration for MPRIS.
 
-use dbus::nonblock::Proxy;
-use dbus::nonblock::stdintf::org_freedesktop_dbus::Properties;
 use dbus::message::MatchRule;
 use dbus::channel::MatchingReceiver;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
-use crate::mpris::connection::{get_active_player_names, is_blocked, TIMEOUT, MprisError};
-use crate::mpris::metadata::{TrackMetadata, extract_metadata};
+use crate::mpris::backend::{DbusBackend, MprisBackend};
+use crate::mpris::connection::{BlockList, is_blocked, selectable, MprisError};
+use crate::mpris::metadata::{PlayerInfo, TrackMetadata, extract_metadata, metadata_from_signal, micros_to_duration};
 
+const MPRIS_ROOT_INTERFACE: &str = "org.mpris.MediaPlayer2";
 const MPRIS_PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
 const DBUS_PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
 const PLAYERCTL_SENDER: &str = "com.github.altdesktop.playerctld";
+/// How long after startup to keep polling for a player if none was found.
+const STARTUP_POLL_WINDOW: Duration = Duration::from_secs(1);
+const STARTUP_POLL_INTERVAL: Duration = Duration::from_millis(200);
 
-pub struct MprisEventHandler<F, G>
+/// Optional behaviour of the event handler.
+pub struct HandlerOptions {
+    /// Look up the next track for `PlayerInfo`
+    pub show_next: bool,
+    /// Look up the player's Identity for `PlayerInfo`
+    pub identity: bool,
+    /// Look up the player's DesktopEntry for `PlayerInfo`
+    pub desktop_entry: bool,
+    /// Read the player's Fullscreen property for `PlayerInfo`
+    pub fullscreen: bool,
+    /// Follow a playing player over a paused one ranked ahead of it
+    pub prefer_playing: bool,
+    /// How often to re-list players without playerctld, which would otherwise
+    /// announce players coming and going (zero disables)
+    pub poll_interval: Duration,
+}
+
+/// Keep the value of a property fetch, recording a failure in `errors`.
+fn record_err<T>(result: Result<T, MprisError>, what: &str, errors: &mut Vec<String>) -> Option<T> {
+    result.map_err(|e| errors.push(format!("{}: {}", what, e))).ok()
+}
+
+pub struct MprisEventHandler<F, G, H, I, B = DbusBackend>
 where
-    F: FnMut(TrackMetadata, f64, String, String) + Send + 'static,
-    G: FnMut(TrackMetadata, f64, String) + Send + 'static,
+    F: FnMut(TrackMetadata, Option<Duration>, String, String) + Send + 'static,
+    G: FnMut(TrackMetadata, Duration, String) + Send + 'static,
+    H: FnMut(String) + Send + 'static,
+    I: FnMut(PlayerInfo) + Send + 'static,
 {
     on_track_change: F,
     on_seek: G,
-    block_list: Arc<Vec<String>>,
+    on_error: H,
+    on_player_info: I,
+    block_list: Arc<BlockList>,
+    options: HandlerOptions,
+    identities: HashMap<String, String>, // Service name -> cached Identity
+    desktop_entries: HashMap<String, String>, // Service name -> cached DesktopEntry
+    last_event: Arc<Mutex<Instant>>, // When the last D-Bus signal arrived
     current_service: String,
     last_track: TrackMetadata,
     last_playback_status: String,
-    conn: Arc<dbus::nonblock::SyncConnection>,
+    // Without playerctld, players are ranked by their last PropertiesChanged signal
+    fallback: bool,
+    last_active: HashMap<String, Instant>,
+    owners: HashMap<String, String>, // Unique bus name -> service name
+    backend: B,
     msg_rx: mpsc::Receiver<dbus::message::Message>,
 }
 
-impl<F, G> MprisEventHandler<F, G>
+impl<F, G, H, I> MprisEventHandler<F, G, H, I, DbusBackend>
 where
-    F: FnMut(TrackMetadata, f64, String, String) + Send + 'static,
-    G: FnMut(TrackMetadata, f64, String) + Send + 'static,
+    F: FnMut(TrackMetadata, Option<Duration>, String, String) + Send + 'static,
+    G: FnMut(TrackMetadata, Duration, String) + Send + 'static,
+    H: FnMut(String) + Send + 'static,
+    I: FnMut(PlayerInfo) + Send + 'static,
 {
     pub async fn new(
         on_track_change: F,
         on_seek: G,
-        block_list: Vec<String>,
+        on_error: H,
+        on_player_info: I,
+        block_list: BlockList,
+        options: HandlerOptions,
+        last_event: Arc<Mutex<Instant>>,
     ) -> Result<Self, MprisError> {
         let (resource, conn) = dbus_tokio::connection::new_session_sync()
             .map_err(|_| MprisError::NoConnection)?;
-        tokio::spawn(async move { resource.await });
+        tokio::spawn(resource);
 
         let (tx, rx) = mpsc::channel::<dbus::message::Message>(8);
 
@@ -51,19 +96,26 @@ where
         let mut handler = Self {
             on_track_change,
             on_seek,
+            on_error,
+            on_player_info,
             block_list: Arc::new(block_list),
+            options,
+            identities: HashMap::new(),
+            desktop_entries: HashMap::new(),
+            last_event,
             current_service: String::new(),
             last_track: TrackMetadata::default(),
             last_playback_status: String::new(),
-            conn,
+            fallback: false,
+            last_active: HashMap::new(),
+            owners: HashMap::new(),
+            backend: DbusBackend::new(conn),
             msg_rx: rx,
         };
 
         // Initial player discovery
-        if let Ok(names) = get_active_player_names().await {
-            if let Some(service) = names.iter().find(|s| !is_blocked(s, &handler.block_list)) {
-                handler.update_current_player(service).await?;
-            }
+        if let Ok(Some(service)) = handler.select_player().await {
+            handler.update_current_player(&service).await?;
         }
 
         Ok(handler)
@@ -86,30 +138,170 @@ where
         );
         Ok(())
     }
+}
+
+impl<F, G, H, I, B> MprisEventHandler<F, G, H, I, B>
+where
+    F: FnMut(TrackMetadata, Option<Duration>, String, String) + Send + 'static,
+    G: FnMut(TrackMetadata, Duration, String) + Send + 'static,
+    H: FnMut(String) + Send + 'static,
+    I: FnMut(PlayerInfo) + Send + 'static,
+    B: MprisBackend,
+{
+    /// Pick the player to follow: the first non-blocked one in playerctld's
+    /// order, or without playerctld the most recently active non-blocked one.
+    async fn select_player(&mut self) -> Result<Option<String>, MprisError> {
+        if let Some(names) = self.backend.playerctld_names().await? {
+            self.fallback = false;
+            let candidates = selectable(names, &self.block_list);
+            return Ok(self.pick(candidates).await);
+        }
+        self.fallback = true;
+        let names = self.backend.list_services().await?;
+        self.refresh_owners(&names).await;
+        let mut candidates = selectable(names, &self.block_list);
+        // Most recently active first; among equals the last listed wins.
+        candidates.reverse();
+        candidates.sort_by_key(|s| std::cmp::Reverse(self.last_active.get(s).copied()));
+        Ok(self.pick(candidates).await)
+    }
+
+    /// The first candidate, or with `prefer_playing` the first one playing.
+    async fn pick(&self, candidates: Vec<String>) -> Option<String> {
+        if self.options.prefer_playing {
+            for service in &candidates {
+                if self.is_playing(service).await {
+                    return Some(service.clone());
+                }
+            }
+        }
+        candidates.into_iter().next()
+    }
+
+    async fn is_playing(&self, service: &str) -> bool {
+        self.backend.playback_status(service).await.is_ok_and(|status| status == "Playing")
+    }
+
+    async fn refresh_owners(&mut self, names: &[String]) {
+        self.owners.clear();
+        for name in names {
+            if let Ok(owner) = self.backend.name_owner(name).await {
+                self.owners.insert(owner, name.clone());
+            }
+        }
+    }
+
+    /// Record activity of the player owning the unique bus name `sender` and
+    /// switch to it. Returns true if the current player changed.
+    async fn track_activity(&mut self, sender: String) -> Result<bool, MprisError> {
+        if !self.owners.contains_key(&sender) {
+            let names = self.backend.list_services().await?;
+            self.refresh_owners(&names).await;
+        }
+        let Some(service) = self.owners.get(&sender).cloned() else {
+            return Ok(false);
+        };
+        self.last_active.insert(service.clone(), Instant::now());
+        // With prefer_playing a paused player doesn't take over from a playing one.
+        let keep_current = self.options.prefer_playing
+            && self.last_playback_status == "Playing"
+            && !self.is_playing(&service).await;
+        if service != self.current_service && !is_blocked(&service, &self.block_list) && !keep_current {
+            self.update_current_player(&service).await?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
 
     async fn update_current_player(&mut self, service: &str) -> Result<(), MprisError> {
-        let proxy = Proxy::new(service, "/org/mpris/MediaPlayer2", TIMEOUT, self.conn.clone());
-        let metadata: Option<dbus::arg::PropMap> = Properties::get(&proxy, MPRIS_PLAYER_INTERFACE, "Metadata").await.ok();
-        let meta = metadata.map(|map| extract_metadata(&map)).unwrap_or_default();
-        let position: f64 = Properties::get::<i64>(&proxy, MPRIS_PLAYER_INTERFACE, "Position").await.ok().map(|p| p as f64 / 1_000_000.0).unwrap_or(0.0);
-        let playback_status: String = Properties::get::<String>(&proxy, MPRIS_PLAYER_INTERFACE, "PlaybackStatus").await.ok().unwrap_or_else(|| "Stopped".to_string());
+        let mut errors = Vec::new();
+        let meta = record_err(self.backend.metadata(service).await, "Metadata", &mut errors).unwrap_or_default();
+        // Players that don't implement Position are tracked from 0 and Seeked signals.
+        let position = self.backend.position(service).await.ok();
+        let playback_status: String = record_err(self.backend.playback_status(service).await, "PlaybackStatus", &mut errors).unwrap_or_else(|| "Stopped".to_string());
 
         self.current_service = service.to_string();
         self.last_track = meta.clone();
         let playback_status_str = playback_status.clone();
         self.last_playback_status = playback_status;
         (self.on_track_change)(meta, position, playback_status_str, service.to_string());
+        if !errors.is_empty() {
+            (self.on_error)(errors.join("; "));
+        }
+        let info = self.fetch_player_info(service).await;
+        (self.on_player_info)(info);
         Ok(())
     }
 
+    /// Read the optional extras for `service` that are enabled: whether it is
+    /// fullscreen, the next track from its TrackList and the player's Identity
+    /// and DesktopEntry.
+    async fn fetch_player_info(&mut self, service: &str) -> PlayerInfo {
+        let fullscreen = self.options.fullscreen && self.backend.fullscreen(service).await.unwrap_or(false);
+        let next_title = if self.options.show_next {
+            self.backend.next_title(service, &self.last_track.track_id).await.unwrap_or_default()
+        } else {
+            String::new()
+        };
+        // Neither name changes while a player is running, so each is read once per service.
+        let identity = match self.identities.get(service) {
+            Some(identity) => identity.clone(),
+            None if self.options.identity => {
+                let identity = self.backend.identity(service).await.unwrap_or_default();
+                self.identities.insert(service.to_string(), identity.clone());
+                identity
+            }
+            None => String::new(),
+        };
+        let desktop_entry = match self.desktop_entries.get(service) {
+            Some(entry) => entry.clone(),
+            None if self.options.desktop_entry => {
+                let entry = self.backend.desktop_entry(service).await.unwrap_or_default();
+                self.desktop_entries.insert(service.to_string(), entry.clone());
+                entry
+            }
+            None => String::new(),
+        };
+        PlayerInfo { fullscreen, next_title, identity, desktop_entry }
+    }
+
     pub async fn handle_events(&mut self) -> Result<(), MprisError> {
-        while let Some(msg) = self.msg_rx.recv().await {
-            self.handle_message(msg).await?;
+        // A player that showed up while the match rules were being added may
+        // not announce itself again, so keep looking for a moment.
+        let deadline = Instant::now() + STARTUP_POLL_WINDOW;
+        while self.current_service.is_empty() && Instant::now() < deadline {
+            tokio::select! {
+                msg = self.msg_rx.recv() => match msg {
+                    Some(msg) => self.handle_message(msg).await?,
+                    None => return Ok(()),
+                },
+                _ = tokio::time::sleep(STARTUP_POLL_INTERVAL) => {
+                    if let Ok(Some(service)) = self.select_player().await {
+                        self.update_current_player(&service).await?;
+                    }
+                }
+            }
+        }
+        let poll_every = self.options.poll_interval;
+        let mut poll = tokio::time::interval(poll_every.max(Duration::from_millis(1)));
+        poll.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                msg = self.msg_rx.recv() => match msg {
+                    Some(msg) => self.handle_message(msg).await?,
+                    None => return Ok(()),
+                },
+                _ = poll.tick(), if self.fallback && !poll_every.is_zero() => {
+                    if let Ok(selected) = self.select_player().await {
+                        self.switch_player(selected).await?;
+                    }
+                }
+            }
         }
-        Ok(())
     }
 
     async fn handle_message(&mut self, msg: dbus::message::Message) -> Result<(), MprisError> {
+        *self.last_event.lock().unwrap() = Instant::now();
         match (msg.interface().as_deref(), msg.member().as_deref()) {
             (Some(MPRIS_PLAYER_INTERFACE), Some("Seeked")) => self.handle_seek(msg).await?,
             (Some(DBUS_PROPERTIES_INTERFACE), _) => self.handle_properties_changed(msg).await?,
@@ -122,17 +314,16 @@ where
         if self.current_service.is_empty() {
             return Ok(());
         }
-        if let Some(pos) = msg.read1::<i64>().ok() {
-            let sec = pos as f64 / 1_000_000.0;
-            (self.on_seek)(self.last_track.clone(), sec, self.current_service.clone());
+        if let Ok(pos) = msg.read1::<i64>() {
+            (self.on_seek)(self.last_track.clone(), micros_to_duration(pos), self.current_service.clone());
         }
         Ok(())
     }
 
     async fn handle_properties_changed(&mut self, msg: dbus::message::Message) -> Result<(), MprisError> {
-        if let Some(interface_name) = msg.read1::<&str>().ok() {
+        if let Ok(interface_name) = msg.read1::<&str>() {
             match interface_name {
-                "org.mpris.MediaPlayer2" | "org.freedesktop.DBus.Properties" | "com.github.altdesktop.playerctld" => {
+                MPRIS_ROOT_INTERFACE | "org.freedesktop.DBus.Properties" | "com.github.altdesktop.playerctld" => {
                     self.handle_player_names_changed(msg).await?;
                 }
                 MPRIS_PLAYER_INTERFACE => {
@@ -146,82 +337,416 @@ where
 
     async fn handle_player_names_changed(&mut self, msg: dbus::message::Message) -> Result<(), MprisError> {
         let changed: Option<dbus::arg::PropMap> = msg.read2().ok().map(|(_, c): (String, dbus::arg::PropMap)| c);
-        if let Some(changed) = changed {
-            if changed.contains_key("PlayerNames") {
-                if let Ok(names) = get_active_player_names().await {
-                    if let Some(service) = names.iter().find(|s| !is_blocked(s, &self.block_list)) {
-                        if *service != self.current_service {
-                            self.update_current_player(service).await?;
-                        }
-                    } else {
-                        // No available player: reset state and trigger output update
-                        self.current_service.clear();
-                        self.last_track = crate::mpris::metadata::TrackMetadata::default();
-                        self.last_playback_status.clear();
-                        (self.on_track_change)(
-                            crate::mpris::metadata::TrackMetadata::default(),
-                            0.0,
-                            String::new(),
-                            String::new(),
-                        );
-                    }
-                }
+        if let Some(changed) = &changed
+            && changed.contains_key("Fullscreen")
+            && self.options.fullscreen
+            && !self.current_service.is_empty()
+        {
+            let service = self.current_service.clone();
+            let info = self.fetch_player_info(&service).await;
+            (self.on_player_info)(info);
+        }
+        if let Some(changed) = changed
+            && changed.contains_key("PlayerNames")
+            && let Ok(selected) = self.select_player().await
+        {
+            self.switch_player(selected).await?;
+        }
+        Ok(())
+    }
+
+    /// Follow the newly selected player, if it's a different one.
+    async fn switch_player(&mut self, selected: Option<String>) -> Result<(), MprisError> {
+        if let Some(service) = selected {
+            if service != self.current_service {
+                self.update_current_player(&service).await?;
             }
+        } else if !self.current_service.is_empty() {
+            // No available player: reset state and trigger output update
+            self.current_service.clear();
+            self.last_track = crate::mpris::metadata::TrackMetadata::default();
+            self.last_playback_status.clear();
+            (self.on_track_change)(
+                crate::mpris::metadata::TrackMetadata::default(),
+                None,
+                String::new(),
+                String::new(),
+            );
+            (self.on_player_info)(PlayerInfo::default());
         }
         Ok(())
     }
 
+    /// Whether the unique bus name `sender` is the current player's, which is
+    /// looked up once and kept in `owners`.
+    async fn sent_by_current(&mut self, sender: Option<String>) -> bool {
+        let Some(sender) = sender else {
+            return false;
+        };
+        if let Some(service) = self.owners.get(&sender) {
+            return *service == self.current_service;
+        }
+        if self.owners.values().any(|service| *service == self.current_service) {
+            return false;
+        }
+        match self.backend.name_owner(&self.current_service).await {
+            Ok(owner) => {
+                self.owners.insert(owner.clone(), self.current_service.clone());
+                owner == sender
+            }
+            Err(_) => false,
+        }
+    }
+
     async fn handle_player_properties_changed(&mut self, msg: dbus::message::Message) -> Result<(), MprisError> {
+        if self.fallback {
+            let changed: Option<dbus::arg::PropMap> = msg.read2().ok().map(|(_, c): (String, dbus::arg::PropMap)| c);
+            let active = changed.is_some_and(|c| c.contains_key("PlaybackStatus") || c.contains_key("Metadata"));
+            if active
+                && let Some(sender) = msg.sender().map(|s| s.to_string())
+                && self.track_activity(sender).await?
+            {
+                return Ok(());
+            }
+        }
         if self.current_service.is_empty() {
             return Ok(());
         }
-        let player_proxy = Proxy::new(
-            &self.current_service,
-            "/org/mpris/MediaPlayer2",
-            TIMEOUT,
-            self.conn.clone(),
-        );
+        let service = self.current_service.clone();
         let changed: Option<dbus::arg::PropMap> = msg.read2().ok().map(|(_, c): (String, dbus::arg::PropMap)| c);
         if let Some(changed) = changed {
             let mut metadata_changed = false;
             let mut status_changed = false;
+            let mut errors = Vec::new();
 
-            if changed.contains_key("Metadata") {
-                if let Ok(metadata) = Properties::get::<dbus::arg::PropMap>(&player_proxy, MPRIS_PLAYER_INTERFACE, "Metadata").await {
-                    let new_track = extract_metadata(&metadata);
-                    if new_track != self.last_track {
-                        self.last_track = new_track;
-                        metadata_changed = true;
-                    }
-                }
+            // The signal usually carries the new metadata inline; only ask the
+            // player for it when it was left out or just invalidated.
+            let invalidated = msg
+                .read3::<String, dbus::arg::PropMap, Vec<String>>()
+                .is_ok_and(|(_, _, invalidated)| invalidated.iter().any(|name| name == "Metadata"));
+            // Every player's signals arrive here, so only the current one's
+            // payload describes the track shown.
+            let sender = msg.sender().map(|s| s.to_string());
+            let inline = match changed.get("Metadata") {
+                Some(_) if !self.sent_by_current(sender).await => Some(None),
+                value => value.map(|value| metadata_from_signal(&*value.0)),
+            };
+            let metadata = if let Some(Some(metadata)) = &inline {
+                Some(extract_metadata(metadata))
+            } else if inline.is_some() || invalidated {
+                // From another player, sent in a shape we can't read, or invalidated.
+                record_err(self.backend.metadata(&service).await, "Metadata", &mut errors)
+            } else {
+                None
+            };
+            if let Some(new_track) = metadata
+                && new_track != self.last_track
+            {
+                self.last_track = new_track;
+                metadata_changed = true;
             }
 
-            if changed.contains_key("PlaybackStatus") {
-                if let Ok(status) = Properties::get::<String>(&player_proxy, MPRIS_PLAYER_INTERFACE, "PlaybackStatus").await {
-                    if status != self.last_playback_status {
-                        self.last_playback_status = status;
-                        status_changed = true;
-                    }
-                }
+            if changed.contains_key("PlaybackStatus")
+                && let Some(status) = record_err(self.backend.playback_status(&service).await, "PlaybackStatus", &mut errors)
+                && status != self.last_playback_status
+            {
+                self.last_playback_status = status;
+                status_changed = true;
             }
 
-            if changed.contains_key("Position") {
-                if let Some(pos_var) = changed.get("Position") {
-                    if let Some(pos) = pos_var.0.as_i64() {
-                        let sec = pos as f64 / 1_000_000.0;
-                        (self.on_seek)(self.last_track.clone(), sec, self.current_service.clone());
-                    }
-                }
+            if let Some(pos_var) = changed.get("Position")
+                && let Some(pos) = pos_var.0.as_i64()
+            {
+                (self.on_seek)(self.last_track.clone(), micros_to_duration(pos), self.current_service.clone());
             }
 
             if metadata_changed || status_changed {
-                let position = Properties::get::<i64>(&player_proxy, MPRIS_PLAYER_INTERFACE, "Position")
-                    .await
-                    .map(|p| p as f64 / 1_000_000.0)
-                    .unwrap_or(0.0);
+                let position = self.backend.position(&service).await.ok();
                 (self.on_track_change)(self.last_track.clone(), position, self.last_playback_status.clone(), self.current_service.clone());
             }
+
+            if metadata_changed && self.options.show_next {
+                let info = self.fetch_player_info(&service).await;
+                (self.on_player_info)(info);
+            }
+
+            if !errors.is_empty() {
+                (self.on_error)(errors.join("; "));
+            }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use dbus::arg::{PropMap, RefArg, Variant};
+    use dbus::message::Message;
+    use tokio::sync::mpsc;
+
+    use super::{DBUS_PROPERTIES_INTERFACE, HandlerOptions, MPRIS_PLAYER_INTERFACE, MprisEventHandler};
+    use crate::mpris::backend::fake::{FakeBackend, FakePlayer};
+    use crate::mpris::connection::BlockList;
+    use crate::mpris::metadata::{PlayerInfo, TrackMetadata};
+
+    type TrackChange = (TrackMetadata, Option<Duration>, String, String);
+
+    /// Callback calls seen by a handler under test.
+    #[derive(Default)]
+    struct Seen {
+        track_changes: Vec<TrackChange>,
+        errors: Vec<String>,
+        infos: Vec<PlayerInfo>,
+    }
+
+    type TestHandler = MprisEventHandler<
+        Box<dyn FnMut(TrackMetadata, Option<Duration>, String, String) + Send>,
+        Box<dyn FnMut(TrackMetadata, Duration, String) + Send>,
+        Box<dyn FnMut(String) + Send>,
+        Box<dyn FnMut(PlayerInfo) + Send>,
+        FakeBackend,
+    >;
+
+    fn handler(backend: FakeBackend, blocked: &[&str]) -> (TestHandler, Arc<Mutex<Seen>>) {
+        let seen = Arc::new(Mutex::new(Seen::default()));
+        let (seen1, seen2, seen3) = (seen.clone(), seen.clone(), seen.clone());
+        let (_tx, msg_rx) = mpsc::channel(8);
+        let handler: TestHandler = MprisEventHandler {
+            on_track_change: Box::new(move |meta, pos, status, service| {
+                seen1.lock().unwrap().track_changes.push((meta, pos, status, service));
+            }),
+            on_seek: Box::new(|_, _, _| {}),
+            on_error: Box::new(move |err| seen2.lock().unwrap().errors.push(err)),
+            on_player_info: Box::new(move |info| seen3.lock().unwrap().infos.push(info)),
+            block_list: Arc::new(BlockList {
+                substrings: blocked.iter().map(|s| s.to_string()).collect(),
+                ..Default::default()
+            }),
+            options: HandlerOptions {
+                show_next: false,
+                identity: false,
+                desktop_entry: false,
+                fullscreen: false,
+                prefer_playing: false,
+                poll_interval: Duration::ZERO,
+            },
+            identities: HashMap::new(),
+            desktop_entries: HashMap::new(),
+            last_event: Arc::new(Mutex::new(Instant::now())),
+            current_service: String::new(),
+            last_track: TrackMetadata::default(),
+            last_playback_status: String::new(),
+            fallback: false,
+            last_active: HashMap::new(),
+            owners: HashMap::new(),
+            backend,
+            msg_rx,
+        };
+        (handler, seen)
+    }
+
+    fn player(owner: &str, title: &str, status: &str) -> FakePlayer {
+        FakePlayer {
+            owner: owner.to_string(),
+            metadata: Some(TrackMetadata { title: title.to_string(), ..Default::default() }),
+            position: Some(Duration::from_secs(5)),
+            status: status.to_string(),
+            fullscreen: false,
+        }
+    }
+
+    fn backend(players: &[(&str, FakePlayer)]) -> FakeBackend {
+        FakeBackend {
+            playerctld: None,
+            players: players.iter().map(|(name, p)| (name.to_string(), p.clone())).collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn follows_first_unblocked_playerctld_player() {
+        let mut fake = backend(&[]);
+        fake.playerctld = Some(vec![
+            "org.mpris.MediaPlayer2.firefox.instance1".to_string(),
+            "org.mpris.MediaPlayer2.mpv".to_string(),
+            "org.mpris.MediaPlayer2.spotify".to_string(),
+        ]);
+        let (mut handler, _) = handler(fake, &["firefox"]);
+        assert_eq!(handler.select_player().await.unwrap().as_deref(), Some("org.mpris.MediaPlayer2.mpv"));
+        assert!(!handler.fallback);
+    }
+
+    #[tokio::test]
+    async fn playerctld_itself_and_repeated_names_are_skipped() {
+        let names = ["playerctld", "mpv", "mpv", "spotify"].map(|name| format!("org.mpris.MediaPlayer2.{}", name));
+        let fake = |names: &[String]| FakeBackend { playerctld: Some(names.to_vec()), ..backend(&[]) };
+
+        let (mut unblocked, _) = handler(fake(&names), &[]);
+        assert_eq!(unblocked.select_player().await.unwrap().as_deref(), Some("org.mpris.MediaPlayer2.mpv"));
+        // Only the aggregator and a blocked player ahead of the real one.
+        let (mut blocked, _) = handler(fake(&names), &["mpv"]);
+        assert_eq!(blocked.select_player().await.unwrap().as_deref(), Some("org.mpris.MediaPlayer2.spotify"));
+    }
+
+    #[tokio::test]
+    async fn prefer_playing_skips_paused_players() {
+        let mut fake = backend(&[
+            ("org.mpris.MediaPlayer2.firefox", player(":1.1", "Tab", "Paused")),
+            ("org.mpris.MediaPlayer2.mpv", player(":1.2", "Song", "Playing")),
+        ]);
+        fake.playerctld = Some(vec!["org.mpris.MediaPlayer2.firefox".to_string(), "org.mpris.MediaPlayer2.mpv".to_string()]);
+        let (mut handler, _) = handler(fake, &[]);
+        assert_eq!(handler.select_player().await.unwrap().as_deref(), Some("org.mpris.MediaPlayer2.firefox"));
+        handler.options.prefer_playing = true;
+        assert_eq!(handler.select_player().await.unwrap().as_deref(), Some("org.mpris.MediaPlayer2.mpv"));
+    }
+
+    #[tokio::test]
+    async fn fallback_picks_most_recently_active_unblocked_player() {
+        let (mut handler, _) = handler(
+            backend(&[
+                ("org.mpris.MediaPlayer2.chromium", player(":1.1", "Tab", "Playing")),
+                ("org.mpris.MediaPlayer2.mpv", player(":1.2", "Song", "Playing")),
+                ("org.mpris.MediaPlayer2.vlc", player(":1.3", "Film", "Paused")),
+            ]),
+            &["chromium"],
+        );
+        let now = Instant::now();
+        handler.last_active.insert("org.mpris.MediaPlayer2.vlc".to_string(), now);
+        handler.last_active.insert("org.mpris.MediaPlayer2.mpv".to_string(), now + Duration::from_secs(1));
+        handler.last_active.insert("org.mpris.MediaPlayer2.chromium".to_string(), now + Duration::from_secs(2));
+        assert_eq!(handler.select_player().await.unwrap().as_deref(), Some("org.mpris.MediaPlayer2.mpv"));
+        assert!(handler.fallback);
+        assert_eq!(handler.owners.get(":1.3").map(String::as_str), Some("org.mpris.MediaPlayer2.vlc"));
+    }
+
+    #[tokio::test]
+    async fn closed_player_is_dropped_on_reselect() {
+        let (mut handler, seen) = handler(backend(&[("org.mpris.MediaPlayer2.mpv", player(":1.2", "Song", "Playing"))]), &[]);
+        let selected = handler.select_player().await.unwrap();
+        handler.switch_player(selected).await.unwrap();
+        assert_eq!(handler.current_service, "org.mpris.MediaPlayer2.mpv");
+
+        handler.backend.players.clear();
+        let selected = handler.select_player().await.unwrap();
+        handler.switch_player(selected).await.unwrap();
+        assert!(handler.current_service.is_empty());
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.track_changes.len(), 2);
+        assert_eq!(seen.track_changes[1].0, TrackMetadata::default());
+    }
+
+    #[tokio::test]
+    async fn switching_player_reports_its_track() {
+        let mut no_position = player(":1.2", "Song", "Playing");
+        no_position.position = None;
+        let (mut handler, seen) = handler(backend(&[("org.mpris.MediaPlayer2.mpv", no_position)]), &[]);
+        handler.update_current_player("org.mpris.MediaPlayer2.mpv").await.unwrap();
+
+        let seen = seen.lock().unwrap();
+        let (meta, pos, status, service) = &seen.track_changes[0];
+        assert_eq!(meta.title, "Song");
+        assert_eq!(*pos, None);
+        assert_eq!(status, "Playing");
+        assert_eq!(service, "org.mpris.MediaPlayer2.mpv");
+        assert!(seen.errors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_metadata_read_is_reported() {
+        let mut broken = player(":1.2", "", "Paused");
+        broken.metadata = None;
+        let (mut handler, seen) = handler(backend(&[("org.mpris.MediaPlayer2.mpv", broken)]), &[]);
+        handler.update_current_player("org.mpris.MediaPlayer2.mpv").await.unwrap();
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.track_changes[0].2, "Paused");
+        assert!(seen.errors[0].starts_with("Metadata: "));
+    }
+
+    /// A PropertiesChanged signal from `sender` carrying a track titled `title`.
+    fn metadata_signal(sender: &str, title: &str) -> Message {
+        let mut metadata = PropMap::new();
+        metadata.insert("xesam:title".to_string(), Variant(Box::new(title.to_string()) as Box<dyn RefArg>));
+        let mut changed = PropMap::new();
+        changed.insert("Metadata".to_string(), Variant(Box::new(metadata) as Box<dyn RefArg>));
+        let mut msg = Message::signal(
+            &"/org/mpris/MediaPlayer2".into(),
+            &DBUS_PROPERTIES_INTERFACE.into(),
+            &"PropertiesChanged".into(),
+        )
+        .append3(MPRIS_PLAYER_INTERFACE, changed, Vec::<String>::new());
+        msg.set_sender(Some(sender.to_string().into()));
+        msg
+    }
+
+    #[tokio::test]
+    async fn inline_metadata_signal_triggers_track_change() {
+        let (mut handler, seen) = handler(backend(&[("org.mpris.MediaPlayer2.mpv", player(":1.2", "Old", "Playing"))]), &[]);
+        handler.update_current_player("org.mpris.MediaPlayer2.mpv").await.unwrap();
+        handler.handle_message(metadata_signal(":1.2", "New")).await.unwrap();
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.track_changes.len(), 2);
+        assert_eq!(seen.track_changes[1].0.title, "New");
+        assert_eq!(seen.track_changes[1].1, Some(Duration::from_secs(5)));
+    }
+
+    #[tokio::test]
+    async fn other_players_metadata_is_not_shown() {
+        let (mut handler, seen) = handler(
+            backend(&[
+                ("org.mpris.MediaPlayer2.firefox", player(":1.1", "Tab", "Playing")),
+                ("org.mpris.MediaPlayer2.mpv", player(":1.2", "Song", "Playing")),
+            ]),
+            &["firefox"],
+        );
+        handler.update_current_player("org.mpris.MediaPlayer2.mpv").await.unwrap();
+        handler.handle_message(metadata_signal(":1.1", "Next tab")).await.unwrap();
+
+        assert_eq!(handler.last_track.title, "Song");
+        assert_eq!(seen.lock().unwrap().track_changes.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn burst_of_events_takes_one_app_lock_each() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use crate::state::AppState;
+
+        // The callbacks in main.rs each lock AppState once; count the locks
+        // the same way for a burst of track changes.
+        let app = Arc::new(Mutex::new(AppState::new()));
+        let locks = Arc::new(AtomicUsize::new(0));
+        let (mut handler, _) = handler(backend(&[("org.mpris.MediaPlayer2.mpv", player(":1.2", "Song", "Playing"))]), &[]);
+        let (app1, locks1) = (app.clone(), locks.clone());
+        handler.on_track_change = Box::new(move |meta, _, _, _| {
+            let mut app = app1.lock().unwrap();
+            locks1.fetch_add(1, Ordering::Relaxed);
+            app.player.update_from_metadata(&meta);
+        });
+        handler.update_current_player("org.mpris.MediaPlayer2.mpv").await.unwrap();
+
+        let events = 100;
+        for i in 0..events {
+            handler.handle_message(metadata_signal(":1.2", &format!("Track {}", i))).await.unwrap();
+        }
+        assert_eq!(locks.load(Ordering::Relaxed), events + 1);
+        assert_eq!(app.lock().unwrap().player.title, format!("Track {}", events - 1));
+    }
+
+    #[tokio::test]
+    async fn fullscreen_is_read_only_when_inhibiting() {
+        let fullscreen = FakePlayer { fullscreen: true, ..player(":1.2", "Film", "Playing") };
+        let (mut handler, seen) = handler(backend(&[("org.mpris.MediaPlayer2.mpv", fullscreen)]), &[]);
+        handler.update_current_player("org.mpris.MediaPlayer2.mpv").await.unwrap();
+        handler.options.fullscreen = true;
+        handler.update_current_player("org.mpris.MediaPlayer2.mpv").await.unwrap();
+
+        let seen = seen.lock().unwrap();
+        assert!(!seen.infos[0].fullscreen);
+        assert!(seen.infos[1].fullscreen);
+    }
+}
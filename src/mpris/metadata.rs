@@ -1,19 +1,33 @@
 //! Minimal track metadata struct and metadata querying for MPRIS.
 
 use dbus::nonblock::Proxy;
+use std::time::Duration;
 use dbus::nonblock::stdintf::org_freedesktop_dbus::Properties;
-use crate::mpris::connection::{get_dbus_conn, TIMEOUT, MprisError};
+use crate::mpris::backend::{DbusBackend, MprisBackend};
+use crate::mpris::connection::{get_active_player_names, get_dbus_conn, MPRIS_PREFIX, TIMEOUT, MprisError};
 
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct TrackMetadata {
     pub title: String,
     pub artist: String,
     pub album: String,
-    pub length: Option<f64>,
+    pub length: Option<Duration>,
+    pub track_id: String,
+    pub year: Option<u16>,
+}
+
+/// Optional extras about the player beyond the current track's metadata.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PlayerInfo {
+    pub fullscreen: bool,
+    pub next_title: String,
+    pub identity: String,
+    pub desktop_entry: String,
 }
 
 /// Helper to extract a string that might be a single value or the first in an array.
-/// The MPRIS spec says artist/album are arrays of strings, but some players send a single string.
+/// The MPRIS spec says artist/album are arrays of strings, but some players send a single string,
+/// and some send the title wrapped in an array.
 fn extract_optional_string(variant: &dbus::arg::Variant<Box<dyn dbus::arg::RefArg + 'static>>) -> Option<String> {
     use dbus::arg::ArgType;
     match variant.0.arg_type() {
@@ -29,23 +43,185 @@ fn extract_optional_string(variant: &dbus::arg::Variant<Box<dyn dbus::arg::RefAr
     }
 }
 
+/// MPRIS times (positions, lengths) are signed microseconds; negative values
+/// are clamped to zero.
+pub fn micros_to_duration(micros: i64) -> Duration {
+    Duration::from_micros(micros.max(0) as u64)
+}
+
+/// Helper to extract the track length from microseconds.
+/// The spec says `mpris:length` is an i64, but players send u64 or even a numeric string.
+fn extract_length(variant: &dbus::arg::Variant<Box<dyn dbus::arg::RefArg + 'static>>) -> Option<Duration> {
+    let micros = variant
+        .0
+        .as_u64()
+        .map(|l| i64::try_from(l).unwrap_or(i64::MAX))
+        .or_else(|| variant.0.as_i64())
+        .or_else(|| variant.0.as_str().and_then(|s| s.trim().parse::<i64>().ok()))?;
+    // A negative length means the player doesn't know it.
+    (micros >= 0).then(|| micros_to_duration(micros))
+}
+
+/// Release year from an ISO 8601 `xesam:contentCreated` date like
+/// `1977-05-25T00:00:00Z`: its leading four digits.
+fn extract_year(variant: &dbus::arg::Variant<Box<dyn dbus::arg::RefArg + 'static>>) -> Option<u16> {
+    let date = variant.0.as_str()?.trim();
+    let year = date.get(..4).filter(|year| year.bytes().all(|b| b.is_ascii_digit()))?;
+    year.parse().ok()
+}
+
 /// Extract metadata fields from a D-Bus property map.
 pub fn extract_metadata(map: &dbus::arg::PropMap) -> TrackMetadata {
-    let title = map.get("xesam:title").and_then(|v| v.0.as_str()).map(str::to_string).unwrap_or_default();
+    let title = map.get("xesam:title").and_then(extract_optional_string).unwrap_or_default();
     let artist = map.get("xesam:artist").and_then(extract_optional_string).unwrap_or_default();
     let album = map.get("xesam:album").and_then(extract_optional_string).unwrap_or_default();
-    let length = map.get("mpris:length").and_then(|v| v.0.as_u64()).map(|l| l as f64 / 1_000_000.0);
-    TrackMetadata { title, artist, album, length }
+    let length = map.get("mpris:length").and_then(extract_length);
+    let track_id = map.get("mpris:trackid").and_then(|v| v.0.as_str()).map(str::to_string).unwrap_or_default();
+    let year = map.get("xesam:contentCreated").and_then(extract_year);
+    TrackMetadata { title, artist, album, length, track_id, year }
+}
+
+/// Turn a `Metadata` value sent inline in a `PropertiesChanged` signal into a
+/// property map. Signals decode it as a generic `a{sv}` of key/variant pairs
+/// rather than a typed `PropMap`.
+pub fn metadata_from_signal(value: &dyn dbus::arg::RefArg) -> Option<dbus::arg::PropMap> {
+    let mut entries = value.as_iter()?;
+    let mut map = dbus::arg::PropMap::new();
+    while let (Some(key), Some(entry)) = (entries.next(), entries.next()) {
+        let inner = entry.as_iter().and_then(|mut v| v.next())?;
+        map.insert(key.as_str()?.to_string(), dbus::arg::Variant(inner.box_clone()));
+    }
+    Some(map)
 }
 
 /// Query metadata for a specific MPRIS player service.
-#[allow(dead_code)]
 pub async fn get_metadata(service: &str) -> Result<TrackMetadata, MprisError> {
     if service.is_empty() {
         return Ok(TrackMetadata::default());
     }
+    DbusBackend::shared().await?.metadata(service).await
+}
+
+/// Query the playback status ("Playing", "Paused" or "Stopped") of a player.
+pub async fn get_playback_status(service: &str) -> Result<String, MprisError> {
+    DbusBackend::shared().await?.playback_status(service).await
+}
+
+
+/// Everything `name` reports as metadata, as JSON. `name` is a full service
+/// name or the part after `org.mpris.MediaPlayer2.`. If no active player
+/// matches, returns the active service names instead.
+pub async fn query_metadata(name: &str) -> Result<Result<serde_json::Value, Vec<String>>, MprisError> {
+    let services = get_active_player_names().await?;
+    let Some(service) = services
+        .iter()
+        .find(|s| *s == name || s.strip_prefix(MPRIS_PREFIX) == Some(name))
+    else {
+        return Ok(Err(services));
+    };
     let conn = get_dbus_conn().await?;
-    let proxy = Proxy::new(service, "/org/mpris/MediaPlayer2", TIMEOUT, conn);
-    let metadata: Option<dbus::arg::PropMap> = Properties::get(&proxy, "org.mpris.MediaPlayer2.Player", "Metadata").await.ok();
-    Ok(metadata.map(|map| extract_metadata(&map)).unwrap_or_default())
+    let proxy = Proxy::new(service.as_str(), "/org/mpris/MediaPlayer2", TIMEOUT, conn);
+    let metadata: dbus::arg::PropMap = Properties::get(&proxy, "org.mpris.MediaPlayer2.Player", "Metadata").await?;
+    let map = metadata
+        .iter()
+        .map(|(key, value)| (key.clone(), refarg_to_json(&value.0)))
+        .collect();
+    Ok(Ok(serde_json::Value::Object(map)))
+}
+
+/// Convert a D-Bus value of any type to JSON.
+fn refarg_to_json(value: &dyn dbus::arg::RefArg) -> serde_json::Value {
+    use dbus::arg::ArgType;
+    match value.arg_type() {
+        ArgType::Boolean => serde_json::Value::Bool(value.as_i64() == Some(1)),
+        ArgType::Double => value.as_f64().into(),
+        ArgType::String | ArgType::ObjectPath | ArgType::Signature => value.as_str().into(),
+        ArgType::Variant => value
+            .as_iter()
+            .and_then(|mut inner| inner.next().map(refarg_to_json))
+            .unwrap_or_default(),
+        ArgType::Array if value.signature().starts_with("a{") => {
+            let mut entries = value.as_iter().into_iter().flatten();
+            let mut map = serde_json::Map::new();
+            while let (Some(key), Some(entry)) = (entries.next(), entries.next()) {
+                let key = key.as_str().map_or_else(|| refarg_to_json(key).to_string(), str::to_string);
+                map.insert(key, refarg_to_json(entry));
+            }
+            serde_json::Value::Object(map)
+        }
+        ArgType::Array | ArgType::Struct => value.as_iter().into_iter().flatten().map(refarg_to_json).collect(),
+        _ => value.as_u64().map_or_else(|| value.as_i64().into(), Into::into),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dbus::arg::{PropMap, RefArg, Variant};
+
+    fn prop(value: impl RefArg + 'static) -> Variant<Box<dyn RefArg>> {
+        Variant(Box::new(value))
+    }
+
+    #[test]
+    fn title_wrapped_in_array_is_unwrapped() {
+        let mut map = PropMap::new();
+        map.insert("xesam:title".into(), prop(vec!["Song".to_string()]));
+        map.insert("xesam:artist".into(), prop(vec!["Artist".to_string()]));
+        map.insert("xesam:album".into(), prop("Album".to_string()));
+        let meta = extract_metadata(&map);
+        assert_eq!(meta.title, "Song");
+        assert_eq!(meta.artist, "Artist");
+        assert_eq!(meta.album, "Album");
+    }
+
+    #[test]
+    fn length_accepts_integers_and_strings() {
+        let mut map = PropMap::new();
+        map.insert("mpris:length".into(), prop(245_000_000u64));
+        assert_eq!(extract_metadata(&map).length, Some(Duration::from_secs(245)));
+        map.insert("mpris:length".into(), prop(245_000_000i64));
+        assert_eq!(extract_metadata(&map).length, Some(Duration::from_secs(245)));
+        map.insert("mpris:length".into(), prop("245000000".to_string()));
+        assert_eq!(extract_metadata(&map).length, Some(Duration::from_secs(245)));
+        map.insert("mpris:length".into(), prop("unknown".to_string()));
+        assert_eq!(extract_metadata(&map).length, None);
+        map.insert("mpris:length".into(), prop(-1i64));
+        assert_eq!(extract_metadata(&map).length, None);
+        // Exact to the microsecond, where f64 seconds would round.
+        map.insert("mpris:length".into(), prop(9_007_199_254_740_993i64));
+        assert_eq!(extract_metadata(&map).length, Some(Duration::from_micros(9_007_199_254_740_993)));
+    }
+
+    #[test]
+    fn year_is_read_from_content_created() {
+        let mut map = PropMap::new();
+        map.insert("xesam:contentCreated".into(), prop("1977-05-25T00:00:00Z".to_string()));
+        assert_eq!(extract_metadata(&map).year, Some(1977));
+        map.insert("xesam:contentCreated".into(), prop("2003".to_string()));
+        assert_eq!(extract_metadata(&map).year, Some(2003));
+        map.insert("xesam:contentCreated".into(), prop("May 1977".to_string()));
+        assert_eq!(extract_metadata(&map).year, None);
+    }
+
+    #[test]
+    fn metadata_is_read_from_signal_payload() {
+        let mut metadata = PropMap::new();
+        metadata.insert("xesam:title".into(), prop("Song".to_string()));
+        metadata.insert("xesam:artist".into(), prop(vec!["Artist".to_string()]));
+        metadata.insert("mpris:length".into(), prop(180_000_000i64));
+        let mut changed = PropMap::new();
+        changed.insert("Metadata".into(), prop(metadata));
+
+        // Round-trip through a message so the map is decoded like a real signal.
+        let msg = dbus::Message::new_signal("/org/mpris/MediaPlayer2", "org.freedesktop.DBus.Properties", "PropertiesChanged")
+            .unwrap()
+            .append2("org.mpris.MediaPlayer2.Player", changed);
+        let (_, changed): (String, PropMap) = msg.read2().unwrap();
+        let map = metadata_from_signal(&*changed["Metadata"].0).unwrap();
+        let meta = extract_metadata(&map);
+        assert_eq!(meta.title, "Song");
+        assert_eq!(meta.artist, "Artist");
+        assert_eq!(meta.length, Some(Duration::from_secs(180)));
+    }
 }
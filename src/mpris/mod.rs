@@ -1,6 +1,9 @@
 //! MPRIS module: re-exports and module declarations for submodules.
 
+pub mod backend;
 pub mod connection;
+pub mod control;
 pub mod metadata;
 pub mod events;
+pub mod tracklist;
 
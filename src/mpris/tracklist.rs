@@ -0,0 +1,26 @@
+//! Optional TrackList support for looking up the upcoming track.
+
+use dbus::Path;
+use dbus::arg::PropMap;
+use dbus::nonblock::{Proxy, SyncConnection};
+use dbus::nonblock::stdintf::org_freedesktop_dbus::Properties;
+use std::sync::Arc;
+use crate::mpris::metadata::extract_metadata;
+
+const TRACKLIST_INTERFACE: &str = "org.mpris.MediaPlayer2.TrackList";
+
+/// Title of the track after `current_track_id`, or `None` if the player has
+/// no TrackList or the current track is last.
+pub async fn get_next_title(proxy: &Proxy<'_, Arc<SyncConnection>>, current_track_id: &str) -> Option<String> {
+    if current_track_id.is_empty() {
+        return None;
+    }
+    let tracks: Vec<Path<'static>> = Properties::get(proxy, TRACKLIST_INTERFACE, "Tracks").await.ok()?;
+    let index = tracks.iter().position(|track| &**track == current_track_id)?;
+    let next = tracks.get(index + 1)?.clone();
+    let (metadata,): (Vec<PropMap>,) = proxy
+        .method_call(TRACKLIST_INTERFACE, "GetTracksMetadata", (vec![next],))
+        .await
+        .ok()?;
+    metadata.first().map(|map| extract_metadata(map).title)
+}
@@ -0,0 +1,66 @@
+//! Destination for status lines: stdout, or a file/FIFO given with `--output`.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+
+/// Writes status lines and remembers the last one written.
+pub struct OutputSink {
+    path: Option<String>,
+    file: Option<File>,
+    pub last_line: String,
+    last_render: u64, // Number of the newest render handed in
+}
+
+impl OutputSink {
+    pub fn new(path: Option<&str>) -> Self {
+        let path = path.map(str::to_string);
+        let file = path.as_deref().and_then(|p| open(p).ok());
+        Self { path, file, last_line: String::new(), last_render: 0 }
+    }
+
+    /// Write `line`, the output of render number `render`, unless it is the
+    /// line last written. A line overtaken by a newer render on its way here
+    /// is dropped, so a stale line can't replace a current one.
+    pub fn write_if_changed(&mut self, render: u64, line: String) {
+        if render < self.last_render {
+            return;
+        }
+        self.last_render = render;
+        if self.last_line != line && self.write_line(&line) {
+            self.last_line = line;
+        }
+    }
+
+    /// Write `line`, returning whether it went out. A failed write reopens
+    /// the file once so a FIFO picks up a reader that reconnected.
+    pub fn write_line(&mut self, line: &str) -> bool {
+        let Some(path) = &self.path else {
+            println!("{}", line);
+            return true;
+        };
+        for _ in 0..2 {
+            if self.file.is_none() {
+                self.file = open(path).ok();
+            }
+            let Some(file) = &mut self.file else {
+                return false;
+            };
+            if writeln!(file, "{}", line).is_ok() {
+                return true;
+            }
+            self.file = None;
+        }
+        false
+    }
+}
+
+/// Open for appending without blocking, so a FIFO with no reader fails
+/// instead of stalling until one shows up.
+fn open(path: &str) -> io::Result<File> {
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+}
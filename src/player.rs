@@ -1,66 +1,178 @@
 // Minimal state data structures for lyrics and player
 
 use crate::mpris::metadata::TrackMetadata;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+/// How long the `track-changed` class stays on after a new track starts.
+pub const TRACK_CHANGE_FLASH: Duration = Duration::from_secs(1);
 
 #[derive(Debug, PartialEq, Default)]
 pub struct PlayerState {
     pub title: String,
     pub artist: String,
     pub album: String,
+    pub track_id: String, // mpris:trackid, so a replayed or re-tagged track still counts as new
+    pub year: Option<u16>,
     pub playing: bool,
     pub status: String,
-    pub position: f64,
+    pub position: Option<Duration>, // None from a new track until its playback update; estimated from then on
     pub err: Option<String>,
-    pub last_position: f64,
+    pub last_position: Duration,
     pub last_update: Option<Instant>,
-    pub length: Option<f64>,
+    pub length: Option<Duration>,
     pub service: Option<String>,
+    pub fullscreen: bool,
+    pub paused_since: Option<Instant>,
+    pub ever_played: bool, // The current player has been seen Playing
+    pub next_title: String,
+    pub identity: String, // The player's Identity with --use-identity, else empty
+    pub desktop_entry: String, // The player's DesktopEntry with --use-desktop-entry, else empty
+    pub last_played: Option<TrackMetadata>, // Track shown before the player stopped or went away
+    pub last_played_service: String, // Service that played last_played
+    pub generation: u64, // Bumped on every track change, so scrolling restarts even for identical text
+    pub pending_pause: Option<(Instant, Option<Duration>)>, // Pause held back by --status-debounce
+    pub track_changed_at: Option<Instant>,
+    pub position_from_seeks: bool, // Player has no Position property; tracked from 0 and Seeked signals
 }
 
 impl PlayerState {
     pub fn update_from_metadata(&mut self, meta: &TrackMetadata) {
+        if meta.title.is_empty() && meta.artist.is_empty() && meta.album.is_empty() {
+            self.remember_track();
+        }
+        if self.has_changed(meta) {
+            self.generation = self.generation.wrapping_add(1);
+        }
         self.title = meta.title.clone();
         self.artist = meta.artist.clone();
         self.album = meta.album.clone();
+        self.track_id = meta.track_id.clone();
+        self.year = meta.year;
         self.length = meta.length;
-        self.position = 0.0;
+        self.position = None;
         self.err = None;
-        self.last_position = 0.0;
+        self.last_position = Duration::ZERO;
         self.last_update = Some(Instant::now());
         // service should be set elsewhere
     }
 
     pub fn set_service(&mut self, service: &str) {
+        if self.service.as_deref() != Some(service) {
+            self.identity.clear();
+            self.desktop_entry.clear();
+            self.ever_played = false;
+            if !service.is_empty() {
+                self.last_played = None;
+            }
+        }
         self.service = Some(service.to_string());
     }
 
     pub fn get_service(&self) -> Option<&str> {
         self.service.as_deref()
     }
-    pub fn update_playback_dbus(&mut self, playback_status: String, position: f64) {
+    pub fn update_playback_dbus(&mut self, playback_status: String, position: Option<Duration>) {
+        // Without a reported position, carry on from the current estimate
+        // (0 for a new track) and remember that it is only an estimate.
+        self.position_from_seeks = position.is_none();
+        let position = position.or_else(|| Some(self.position.map_or(Duration::ZERO, |_| self.estimate_position())));
         self.playing = playback_status == "Playing";
+        self.ever_played |= self.playing;
+        if playback_status == "Stopped" {
+            self.remember_track();
+        }
+        if self.playing {
+            self.paused_since = None;
+        } else if self.paused_since.is_none() {
+            self.paused_since = Some(Instant::now());
+        }
         self.status = playback_status;
-        self.last_position = position;
+        self.last_position = position.unwrap_or_default();
         self.last_update = Some(Instant::now());
         self.position = position;
     }
-    pub fn estimate_position(&self) -> f64 {
-        if self.playing {
-            if let Some(instant) = self.last_update {
-                let elapsed = instant.elapsed().as_secs_f64();
-                return self.last_position + elapsed;
-            }
+    /// Keep the current track for --format-stopped, if there is one.
+    fn remember_track(&mut self) {
+        if !self.title.is_empty() || !self.artist.is_empty() || !self.album.is_empty() {
+            self.last_played = Some(TrackMetadata {
+                title: self.title.clone(),
+                artist: self.artist.clone(),
+                album: self.album.clone(),
+                length: self.length,
+                track_id: self.track_id.clone(),
+                year: self.year,
+            });
+            self.last_played_service = self.service.clone().unwrap_or_default();
+        }
+    }
+    pub fn estimate_position(&self) -> Duration {
+        if self.playing
+            && let Some(instant) = self.last_update
+        {
+            return self.last_position + instant.elapsed();
         }
         self.last_position
     }
-    #[allow(dead_code)]
+    /// Time until the whole seconds shown for the position next change: those
+    /// of the elapsed time, or with `remaining` those of the time left.
+    pub fn until_next_second(&self, remaining: bool) -> Duration {
+        let elapsed = self.estimate_position();
+        let nanos = match self.length {
+            Some(length) if remaining => match length.saturating_sub(elapsed).subsec_nanos() {
+                0 => 1_000_000_000,
+                nanos => nanos,
+            },
+            _ => 1_000_000_000 - elapsed.subsec_nanos(),
+        };
+        Duration::from_nanos(nanos.into())
+    }
+    /// Identifies the current track across restarts (--persist-scroll).
+    pub fn track_key(&self) -> String {
+        format!("{}\n{}\n{}\n{}", self.service.as_deref().unwrap_or_default(), self.title, self.artist, self.album)
+    }
     pub fn has_changed(&self, meta: &TrackMetadata) -> bool {
-        self.title != meta.title || self.artist != meta.artist || self.album != meta.album
+        self.title != meta.title || self.artist != meta.artist || self.album != meta.album || self.track_id != meta.track_id
+    }
+    /// Snapshot of the state for external consumers.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "title": self.title,
+            "artist": self.artist,
+            "album": self.album,
+            "position": self.position.map(|_| self.estimate_position().as_secs_f64()),
+            "position_estimated": self.position_from_seeks,
+            "length": self.length.map(|length| length.as_secs_f64()),
+            "status": self.status,
+            "service": self.service,
+        })
     }
-    pub fn reset_position_cache(&mut self, position: f64) {
+    pub fn reset_position_cache(&mut self, position: Duration) {
         self.last_position = position;
         self.last_update = Some(Instant::now());
-        self.position = position;
+        self.position = Some(position);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PlayerState;
+    use crate::mpris::metadata::TrackMetadata;
+
+    #[test]
+    fn new_track_id_with_same_tags_bumps_generation() {
+        let meta = |track_id: &str| TrackMetadata {
+            title: "Intro".to_string(),
+            artist: "The xx".to_string(),
+            track_id: track_id.to_string(),
+            ..Default::default()
+        };
+        let mut player_state = PlayerState::default();
+        player_state.update_from_metadata(&meta("/track/1"));
+        let generation = player_state.generation;
+        player_state.update_from_metadata(&meta("/track/1"));
+        assert_eq!(player_state.generation, generation);
+        assert!(player_state.has_changed(&meta("/track/2")));
+        player_state.update_from_metadata(&meta("/track/2"));
+        assert_eq!(player_state.generation, generation + 1);
     }
 }
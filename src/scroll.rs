@@ -1,8 +1,17 @@
-/// Spacer used for wrapping scroll mode.
-pub const WRAP_SPACER: &str = "   ";
-/// Number of cycles to hold at the start/end in reset mode.
+use std::fs;
+use std::io;
+use std::path::Path;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Default number of spaces between the end of the text and its start again
+/// in wrapping mode.
+pub const WRAP_GAP: usize = 3;
+/// Default number of cycles to hold at the start/end in reset mode.
 pub const RESET_HOLD: usize = 2;
 
+/// Shortest frame delay (in milliseconds) adaptive speed will use.
+pub const MIN_ADAPTIVE_DELAY: u64 = 50;
+
 /// Scroll mode for the text output.
 #[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
 pub enum ScrollMode {
@@ -16,8 +25,36 @@ pub enum ScrollMode {
 #[derive(Debug)]
 pub struct ScrollState {
     pub offset: usize,
-    pub hold: usize, // Only used for reset mode
+    pub hold: usize, // Start hold in wrapping mode, start/end hold in reset mode
     pub last_text: String,
+    pub last_frame: String, // Frame returned by the last scroll step
+    pub cycle_len: usize, // Frames in one full scroll cycle, 0 if the text fits
+    pub format_index: usize, // Which of --cycle-formats is shown
+    pub generation: u64, // PlayerState::generation of the track being scrolled
+    pub saved: Option<SavedScroll>, // Left by the last run (--persist-scroll), until resumed
+}
+
+/// Scroll position kept across restarts by --persist-scroll.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SavedScroll {
+    pub track: String, // PlayerState::track_key of the scrolled track
+    pub text: String,
+    pub frame: String,
+    pub offset: usize,
+    pub hold: usize,
+}
+
+impl SavedScroll {
+    /// Read and remove the state file at `path`, if there is a usable one.
+    pub fn take(path: &Path) -> Option<Self> {
+        let text = fs::read_to_string(path).ok()?;
+        let _ = fs::remove_file(path);
+        serde_json::from_str(&text).ok()
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, serde_json::to_string(self)?)
+    }
 }
 
 impl ScrollState {
@@ -26,9 +63,49 @@ impl ScrollState {
             offset: 0,
             hold: 0,
             last_text: String::new(),
+            last_frame: String::new(),
+            cycle_len: 0,
+            format_index: 0,
+            generation: 0,
+            saved: None,
         }
     }
 
+    /// Snapshot of where `track` is in its scroll.
+    pub fn save(&self, track: String) -> SavedScroll {
+        SavedScroll {
+            track,
+            text: self.last_text.clone(),
+            frame: self.last_frame.clone(),
+            offset: self.offset,
+            hold: self.hold,
+        }
+    }
+
+    /// Carry on from the saved position once the same track shows the same
+    /// text again. Another track discards it.
+    pub fn resume(&mut self, track: &str, text: &str) {
+        match self.saved.take() {
+            Some(saved) if saved.track == track && saved.text == text => {
+                self.last_text = saved.text;
+                self.last_frame = saved.frame;
+                self.offset = saved.offset;
+                self.hold = saved.hold;
+            }
+            // Same track, but e.g. still peeking: wait for the scrolled text.
+            Some(saved) if saved.track == track => self.saved = Some(saved),
+            _ => {}
+        }
+    }
+
+    /// Start over from the beginning of the text for a new track.
+    pub fn restart(&mut self, generation: u64) {
+        self.generation = generation;
+        self.last_text.clear();
+        self.offset = 0;
+        self.hold = 0;
+    }
+
     fn reset_if_needed(&mut self, text: &str) {
         if text != self.last_text {
             self.last_text = text.to_string();
@@ -38,40 +115,180 @@ impl ScrollState {
     }
 }
 
+/// How `scroll` moves the text.
+#[derive(Debug, Clone, Copy)]
+pub struct ScrollOptions {
+    pub width: usize,
+    pub mode: ScrollMode,
+    /// Cycles wrapping mode holds at offset 0 before it starts moving
+    pub wrap_start_hold: usize,
+    /// Spaces wrapping mode keeps between the end of the text and its start,
+    /// at least one so the last word never touches the first
+    pub gap: usize,
+    /// Cycles reset mode holds at both the start and the end
+    pub reset_hold: usize,
+    /// Spaces added around text that needs to scroll in reset mode, so its
+    /// ends aren't flush with the edges while holding
+    pub padding: usize,
+    /// Characters the text moves per frame
+    pub step: usize,
+}
+
 /// Scroll text according to mode and width.
-pub fn scroll(text: &str, state: &mut ScrollState, width: usize, mode: ScrollMode) -> String {
+pub fn scroll(text: &str, state: &mut ScrollState, options: &ScrollOptions) -> String {
     state.reset_if_needed(text);
-    match mode {
+    let width = options.width;
+    let step = options.step.max(1);
+    // Nothing to show, so nothing to scroll through.
+    if width == 0 {
+        state.cycle_len = 0;
+        return String::new();
+    }
+    match options.mode {
         ScrollMode::Wrapping => {
-            let padded = format!("{}{}", text, WRAP_SPACER);
-            let chars: Vec<char> = padded.chars().collect();
-            if chars.len() <= width {
+            let padded = format!("{}{}", text, " ".repeat(options.gap.max(1)));
+            let graphemes: Vec<&str> = padded.graphemes(true).collect();
+            if graphemes.len() <= width {
+                state.cycle_len = 0;
                 return text.to_string();
             }
+            state.cycle_len = graphemes.len().div_ceil(step) + options.wrap_start_hold;
             let frame: String = (0..width)
-                .map(|i| chars[(state.offset + i) % chars.len()])
+                .map(|i| graphemes[(state.offset + i) % graphemes.len()])
                 .collect();
-            state.offset = state.offset.wrapping_add(1);
+            if state.offset == 0 && state.hold < options.wrap_start_hold {
+                state.hold += 1;
+            } else {
+                // Wrap back to exactly 0 so every cycle starts, and holds, there.
+                state.offset += step;
+                if state.offset >= graphemes.len() {
+                    state.offset = 0;
+                    state.hold = 0;
+                }
+            }
             frame
         }
         ScrollMode::Reset => {
-            let chars: Vec<char> = text.chars().collect();
-            if chars.len() <= width {
+            // Text that fits is shown as is; padding only applies once it scrolls.
+            if text.graphemes(true).count() <= width {
+                state.cycle_len = 0;
                 return text.to_string();
             }
-            let max_offset = chars.len() - width;
-            let frame: String = chars.iter().skip(state.offset).take(width).collect();
+            let pad = " ".repeat(options.padding);
+            let padded = format!("{}{}{}", pad, text, pad);
+            let graphemes: Vec<&str> = padded.graphemes(true).collect();
+            let max_offset = graphemes.len() - width;
+            state.cycle_len = max_offset.div_ceil(step) + 1 + 2 * options.reset_hold;
+            let frame: String = graphemes.iter().skip(state.offset).take(width).copied().collect();
             if state.offset == 0 || state.offset == max_offset {
-                if state.hold < RESET_HOLD {
+                if state.hold < options.reset_hold {
                     state.hold += 1;
                 } else {
                     state.hold = 0;
-                    state.offset = if state.offset == max_offset { 0 } else { state.offset + 1 };
+                    state.offset = if state.offset == max_offset { 0 } else { step.min(max_offset) };
                 }
             } else {
-                state.offset += 1;
+                // Land exactly on the end so it gets its hold.
+                state.offset = (state.offset + step).min(max_offset);
             }
             frame
         }
     }
-}
\ No newline at end of file
+}
+
+/// Frame delay that makes a full scroll cycle take about as long as `width`
+/// frames at `base_delay`, so short and long texts loop at a similar pace.
+pub fn adaptive_delay(base_delay: u64, width: usize, cycle_len: usize) -> u64 {
+    if cycle_len == 0 {
+        return base_delay;
+    }
+    (base_delay * width as u64 / cycle_len as u64).clamp(MIN_ADAPTIVE_DELAY, base_delay * 4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ScrollMode, ScrollOptions, ScrollState, scroll};
+
+    fn options(width: usize, mode: ScrollMode) -> ScrollOptions {
+        ScrollOptions { width, mode, wrap_start_hold: 0, gap: 3, reset_hold: 0, padding: 0, step: 1 }
+    }
+
+    #[test]
+    fn zero_width_shows_nothing_and_does_not_scroll() {
+        for mode in [ScrollMode::Wrapping, ScrollMode::Reset] {
+            let mut state = ScrollState::new();
+            for _ in 0..3 {
+                assert_eq!(scroll("Some long title", &mut state, &options(0, mode)), "");
+            }
+            assert_eq!(state.offset, 0);
+            assert_eq!(state.cycle_len, 0);
+        }
+    }
+
+    #[test]
+    fn frames_never_split_grapheme_clusters() {
+        // A combining accent and a two-codepoint flag each count as one.
+        let text = "Cafe\u{301} 🇫🇷 Radio";
+        for mode in [ScrollMode::Wrapping, ScrollMode::Reset] {
+            let mut state = ScrollState::new();
+            let frames: Vec<String> = (0..16).map(|_| scroll(text, &mut state, &options(4, mode))).collect();
+            assert!(frames.iter().any(|frame| frame.starts_with("e\u{301}")));
+            assert!(frames.iter().any(|frame| frame.contains("🇫🇷")));
+            for frame in &frames {
+                assert!(!frame.starts_with('\u{301}'));
+                assert_eq!(frame.matches('\u{1F1EB}').count(), frame.matches('\u{1F1F7}').count());
+            }
+        }
+    }
+
+    #[test]
+    fn resume_continues_only_the_saved_track() {
+        let text = "A title much longer than the width";
+        let mut state = ScrollState::new();
+        for _ in 0..6 {
+            scroll(text, &mut state, &options(8, ScrollMode::Wrapping));
+        }
+        let saved = state.save("spotify\nTitle".to_string());
+
+        let mut restarted = ScrollState { saved: Some(saved.clone()), ..ScrollState::new() };
+        restarted.resume("spotify\nTitle", "peek text");
+        restarted.resume("spotify\nTitle", text);
+        assert_eq!(restarted.offset, 6);
+        assert_eq!(scroll(text, &mut restarted, &options(8, ScrollMode::Wrapping)), scroll(text, &mut state, &options(8, ScrollMode::Wrapping)));
+
+        let mut other = ScrollState { saved: Some(saved), ..ScrollState::new() };
+        other.resume("mpv\nOther", text);
+        assert!(other.saved.is_none());
+        assert_eq!(other.offset, 0);
+    }
+
+    #[test]
+    fn wrap_gap_is_kept_across_the_wrap_boundary() {
+        for gap in [0, 1, 2, 5] {
+            let mut state = ScrollState::new();
+            let options = ScrollOptions { gap, ..options(6, ScrollMode::Wrapping) };
+            let spaces = " ".repeat(gap.max(1));
+            for _ in 0..20 {
+                let frame = scroll("abcdef", &mut state, &options);
+                // Wherever the end and the start are both visible, the full gap sits between them.
+                if let (Some(end), Some(start)) = (frame.find('f'), frame.find('a'))
+                    && end < start
+                {
+                    assert_eq!(&frame[end + 1..start], spaces, "gap {} in {:?}", gap, frame);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn wrapping_with_a_step_returns_to_the_start_every_cycle() {
+        // "abcdefg" plus a 3-space gap is 10 graphemes: offsets 0, 3, 6, 9, then 0 again.
+        let mut state = ScrollState::new();
+        let options = ScrollOptions { step: 3, wrap_start_hold: 1, ..options(4, ScrollMode::Wrapping) };
+        let frames: Vec<String> = (0..10).map(|_| scroll("abcdefg", &mut state, &options)).collect();
+        assert_eq!(state.cycle_len, 5);
+        assert_eq!(frames[..5], ["abcd", "abcd", "defg", "g   ", " abc"]);
+        // The start hold applies again on the next cycle.
+        assert_eq!(frames[5..], ["abcd", "abcd", "defg", "g   ", " abc"]);
+    }
+}
@@ -0,0 +1,148 @@
+//! Unix socket exposing the current player state to scripts, and taking
+//! commands to control the display and the player.
+//!
+//! Every connection first receives the state as one JSON line. After that
+//! each line sent is a command, answered with `ok` or `error: <reason>`:
+//!
+//! - `status`: the state JSON again
+//! - `toggle-position`: show or hide the position
+//! - `set-format <format>`: replace --format (and stop --cycle-formats)
+//! - `next`, `prev`, `play-pause`: sent on to the player being shown
+
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+
+use crate::mpris::control;
+use crate::state::{AppState, SharedConfig};
+
+/// A command line read from a connection.
+#[derive(Debug, PartialEq)]
+enum Command {
+    Status,
+    TogglePosition,
+    SetFormat(String),
+    /// MPRIS Player method to call
+    Player(&'static str),
+}
+
+impl Command {
+    fn parse(line: &str) -> Result<Self, String> {
+        let line = line.trim();
+        let (name, arg) = line.split_once(' ').unwrap_or((line, ""));
+        match (name, arg.trim()) {
+            ("status", "") => Ok(Self::Status),
+            ("toggle-position", "") => Ok(Self::TogglePosition),
+            ("set-format", "") => Err("set-format needs a format".to_string()),
+            ("set-format", format) => Ok(Self::SetFormat(format.to_string())),
+            ("next", "") => Ok(Self::Player("Next")),
+            ("prev", "") => Ok(Self::Player("Previous")),
+            ("play-pause", "") => Ok(Self::Player("PlayPause")),
+            ("status" | "toggle-position" | "next" | "prev" | "play-pause", _) => {
+                Err(format!("{} takes no argument", name))
+            }
+            _ => Err(format!("unknown command: {}", name)),
+        }
+    }
+}
+
+/// Accept connections on `path`, answering each with the current state as
+/// JSON and then running its commands. `redraw` is poked after a change.
+pub async fn serve(
+    path: &str,
+    app: Arc<Mutex<AppState>>,
+    config: SharedConfig,
+    redraw: mpsc::Sender<()>,
+) -> io::Result<()> {
+    // A stale socket from a previous run would make bind fail.
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let (app, config, redraw) = (app.clone(), config.clone(), redraw.clone());
+        tokio::spawn(async move {
+            let _ = handle_connection(stream, app, config, redraw).await;
+        });
+    }
+}
+
+async fn handle_connection(
+    stream: UnixStream,
+    app: Arc<Mutex<AppState>>,
+    config: SharedConfig,
+    redraw: mpsc::Sender<()>,
+) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let state = format!("{}\n", app.lock().unwrap().player.to_json());
+    writer.write_all(state.as_bytes()).await?;
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match Command::parse(&line) {
+            Ok(command) => run(command, &app, &config, &redraw).await,
+            Err(e) => Err(e),
+        };
+        let reply = match reply {
+            Ok(None) => "ok\n".to_string(),
+            Ok(Some(text)) => format!("{}\n", text),
+            Err(e) => format!("error: {}\n", e),
+        };
+        writer.write_all(reply.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Run `command`, returning the reply if it is more than `ok`.
+async fn run(
+    command: Command,
+    app: &Mutex<AppState>,
+    config: &SharedConfig,
+    redraw: &mpsc::Sender<()>,
+) -> Result<Option<String>, String> {
+    match command {
+        Command::Status => return Ok(Some(app.lock().unwrap().player.to_json().to_string())),
+        Command::TogglePosition => update_config(config, |config| config.position_enabled = !config.position_enabled),
+        Command::SetFormat(format) => update_config(config, |config| {
+            config.format = format;
+            config.cycle_formats.clear();
+        }),
+        Command::Player(method) => {
+            let service = app.lock().unwrap().player.get_service().unwrap_or_default().to_string();
+            if service.is_empty() {
+                return Err("no player".to_string());
+            }
+            control::call(service, method).await.map_err(|e| e.to_string())?;
+        }
+    }
+    let _ = redraw.try_send(());
+    Ok(None)
+}
+
+/// Apply `change` to a copy of the live config and swap it in. Like any
+/// command-line option, this lasts until the next SIGHUP reload.
+fn update_config(config: &SharedConfig, change: impl FnOnce(&mut crate::config::Config)) {
+    let mut config = config.write().unwrap();
+    let mut updated = (**config).clone();
+    change(&mut updated);
+    *config = Arc::new(updated);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Command;
+
+    #[test]
+    fn parses_commands_and_arguments() {
+        assert_eq!(Command::parse("toggle-position\n"), Ok(Command::TogglePosition));
+        assert_eq!(Command::parse("set-format {artist} - {title}"), Ok(Command::SetFormat("{artist} - {title}".to_string())));
+        assert_eq!(Command::parse("prev"), Ok(Command::Player("Previous")));
+        assert!(Command::parse("set-format").is_err());
+        assert!(Command::parse("next now").is_err());
+        assert!(Command::parse("volume 50").is_err());
+    }
+}
@@ -0,0 +1,38 @@
+//! State shared between the event handler, the printer and the main loop.
+
+use std::sync::{Arc, RwLock};
+
+use crate::config::Config;
+use crate::player::PlayerState;
+use crate::scroll::ScrollState;
+
+/// The live config: swapped whole on a SIGHUP reload or a socket command,
+/// and read afresh by each render.
+pub type SharedConfig = Arc<RwLock<Arc<Config>>>;
+
+/// Everything a render touches, kept behind one lock so a tick or an event
+/// takes a single `Mutex` instead of one per piece. The output sink has its
+/// own lock: the line is rendered under this one and written after it is
+/// released, so a slow reader never blocks the event handler.
+pub struct AppState {
+    pub player: PlayerState,
+    pub scroll: ScrollState,
+    renders: u64, // Renders so far, numbering each line for the sink
+}
+
+impl AppState {
+    pub fn new() -> Self {
+        Self {
+            player: PlayerState::default(),
+            scroll: ScrollState::new(),
+            renders: 0,
+        }
+    }
+
+    /// Number the next render. Taken under this lock, so the numbers follow
+    /// the order the renders saw the state in.
+    pub fn next_render(&mut self) -> u64 {
+        self.renders += 1;
+        self.renders
+    }
+}
@@ -1,48 +1,367 @@
 use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::PathBuf;
+use std::time::Duration;
 
-use crate::config::{Config, PositionMode, ScrollMode as ConfigScrollMode};
-use crate::player::PlayerState;
-use crate::scroll::{ScrollMode, ScrollState, scroll};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Serialize;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
-fn format_metadata(format: &str, title: &str, artist: &str, album: &str) -> String {
-    format
-        .replace("{title}", title.trim())
-        .replace("{artist}", artist.trim())
-        .replace("{album}", album.trim())
-        .trim()
-        .to_string()
+use crate::config::{Align, Config, IconPosition, FreezeMode, OutputFormat, PositionMode, PositionPrecision, PositionWhen, ScrollMode as ConfigScrollMode, TextCase};
+use crate::mpris::connection::base_name;
+use crate::player::{PlayerState, TRACK_CHANGE_FLASH};
+use crate::scroll::{ScrollMode, ScrollOptions, ScrollState, scroll};
+
+/// Fill `{name}` placeholders in `format` from `fields`. Text between two
+/// placeholders is treated as a separator and only kept when there is content
+/// on both sides, so `{artist} - {title}` collapses to just the title when the
+/// artist is missing. Text before the first and after the last placeholder is
+/// kept, except for brackets that pair with one in the separator next to the
+/// outer field: those go with that field, so `{title} ({album})` without an
+/// album is just the title. Unknown placeholders are left as literal text.
+/// With `dedupe` a field repeating one already shown counts as empty, so
+/// `{title} - {artist}` with both set to the same name shows it once.
+fn format_metadata(format: &str, fields: &[(&str, &str)], dedupe: bool) -> String {
+    enum Segment<'a> {
+        Literal(&'a str),
+        // A value with the brackets around it that are only shown along with it
+        Field { value: &'a str, before: &'a str, after: &'a str },
+    }
+
+    let mut segments = Vec::new();
+    let mut rest = format;
+    let mut literal_start = 0;
+    while let Some(open) = rest[literal_start..].find('{').map(|i| i + literal_start) {
+        let field = rest[open + 1..]
+            .find('}')
+            .map(|close| &rest[open + 1..open + 1 + close])
+            .and_then(|name| fields.iter().find(|(key, _)| *key == name));
+        match field {
+            Some((name, value)) => {
+                if open > 0 {
+                    segments.push(Segment::Literal(&rest[..open]));
+                }
+                segments.push(Segment::Field { value: value.trim(), before: "", after: "" });
+                rest = &rest[open + name.len() + 2..];
+                literal_start = 0;
+            }
+            None => literal_start = open + 1,
+        }
+    }
+    if !rest.is_empty() {
+        segments.push(Segment::Literal(rest));
+    }
+
+    let first_field = segments.iter().position(|s| matches!(s, Segment::Field { .. }));
+    let last_field = segments.iter().rposition(|s| matches!(s, Segment::Field { .. }));
+    if let (Some(first), Some(last)) = (first_field, last_field)
+        && first < last
+    {
+        // `(` of `({album}) {title}`: opened before the first field, closed in the separator after it.
+        if first == 1
+            && let (Segment::Literal(leading), Segment::Literal(separator)) = (&segments[0], &segments[2])
+        {
+            let (outer, opening) = leading.split_at(leading.len() - opening_len(leading, separator));
+            segments[0] = Segment::Literal(outer);
+            if let Segment::Field { before, .. } = &mut segments[1] {
+                *before = opening;
+            }
+        }
+        // `)` of `{title} ({album})`: opened in the separator before the last field, closed after it.
+        if let (Segment::Literal(separator), Some(Segment::Literal(trailing))) = (&segments[last - 1], segments.get(last + 1)) {
+            let (closing, outer) = trailing.split_at(closing_len(separator, trailing));
+            segments[last + 1] = Segment::Literal(outer);
+            if let Segment::Field { after, .. } = &mut segments[last] {
+                *after = closing;
+            }
+        }
+    }
+
+    let mut out = String::new();
+    let mut seen_field = false;
+    let mut has_content = false;
+    let mut separator = None;
+    let mut shown: Vec<&str> = Vec::new();
+    for (i, segment) in segments.iter().enumerate() {
+        match *segment {
+            Segment::Literal(text) if !seen_field || last_field.is_none_or(|last| i > last) => out.push_str(text),
+            Segment::Literal(text) => separator = Some(text),
+            Segment::Field { value, before, after } => {
+                seen_field = true;
+                if value.is_empty() || (dedupe && shown.contains(&value)) {
+                    continue;
+                }
+                shown.push(value);
+                if has_content && let Some(sep) = separator.take() {
+                    out.push_str(sep);
+                }
+                separator = None;
+                out.push_str(before);
+                out.push_str(value);
+                out.push_str(after);
+                has_content = true;
+            }
+        }
+    }
+    out.trim().to_string()
+}
+
+/// Brackets that can wrap a field across a separator.
+const BRACKETS: [(char, char); 2] = [('(', ')'), ('[', ']')];
+
+/// Length of the start of `text` that closes brackets left open in `separator`.
+fn closing_len(separator: &str, text: &str) -> usize {
+    let mut open = Vec::new();
+    for c in separator.chars() {
+        if let Some(&(_, close)) = BRACKETS.iter().find(|(open, _)| *open == c) {
+            open.push(close);
+        } else if BRACKETS.iter().any(|(_, close)| *close == c) {
+            open.pop();
+        }
+    }
+    text.chars().take_while(|&c| open.pop() == Some(c)).map(char::len_utf8).sum()
+}
+
+/// Length of the end of `text` that opens brackets closed in `separator`.
+fn opening_len(text: &str, separator: &str) -> usize {
+    let mut closed = Vec::new();
+    for c in separator.chars().rev() {
+        if let Some(&(open, _)) = BRACKETS.iter().find(|(_, close)| *close == c) {
+            closed.push(open);
+        } else if BRACKETS.iter().any(|(open, _)| *open == c) {
+            closed.pop();
+        }
+    }
+    text.chars().rev().take_while(|&c| closed.pop() == Some(c)).map(char::len_utf8).sum()
+}
+
+/// Change the letter case of `text`. Title case uppercases the first letter
+/// of each whitespace-separated word and leaves the rest alone.
+fn apply_case(text: &str, case: TextCase) -> String {
+    match case {
+        TextCase::None => text.to_string(),
+        TextCase::Upper => text.to_uppercase(),
+        TextCase::Lower => text.to_lowercase(),
+        TextCase::Title => {
+            let mut out = String::with_capacity(text.len());
+            let mut word_start = true;
+            for c in text.chars() {
+                if word_start {
+                    out.extend(c.to_uppercase());
+                } else {
+                    out.push(c);
+                }
+                word_start = c.is_whitespace();
+            }
+            out
+        }
+    }
+}
+
+/// Format `fields`, blanking them one at a time in `drop_order` until the
+/// text fits in `width` characters or there is nothing left to drop.
+fn compact_metadata(format: &str, fields: &[(&str, &str)], drop_order: &[String], width: usize, dedupe: bool) -> String {
+    let mut fields = fields.to_vec();
+    let mut formatted = format_metadata(format, &fields, dedupe);
+    for name in drop_order {
+        if formatted.graphemes(true).count() <= width {
+            break;
+        }
+        if let Some(field) = fields.iter_mut().find(|(key, _)| key == name) {
+            field.1 = "";
+            formatted = format_metadata(format, &fields, dedupe);
+        }
+    }
+    formatted
+}
+
+/// Marks where the scrolling field sits in the formatted text.
+const SCROLL_FIELD_MARKER: &str = "\u{1}";
+
+/// Format `format` around the placeholder `field`, returning the static text
+/// before it, its value and the static text after it. `None` if the field is
+/// empty or not used by the format, in which case the whole text scrolls.
+fn split_scroll_field(format: &str, fields: &[(&str, &str)], field: &str, dedupe: bool) -> Option<(String, String, String)> {
+    let value = fields.iter().find(|(name, _)| *name == field)?.1.trim();
+    if value.is_empty() {
+        return None;
+    }
+    let marked: Vec<(&str, &str)> = fields
+        .iter()
+        .map(|&(name, v)| if name == field { (name, SCROLL_FIELD_MARKER) } else { (name, v) })
+        .collect();
+    let formatted = format_metadata(format, &marked, dedupe);
+    let (prefix, suffix) = formatted.split_once(SCROLL_FIELD_MARKER)?;
+    Some((prefix.to_string(), value.to_string(), suffix.to_string()))
 }
 
+/// Remove control characters (newlines, escapes, ...), which garble the bar or
+/// break the JSON line. With `invisible`, also remove invisible format
+/// characters like zero-width spaces and direction marks, but keep a
+/// zero-width joiner between two emoji so sequences like 👩‍💻 stay whole.
+fn sanitize(text: &str, invisible: bool) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let joins_emoji = |i: usize| {
+        let emoji = |c: Option<&char>| c.is_some_and(|c| !c.is_ascii() && !c.is_alphanumeric() && !c.is_whitespace());
+        emoji(i.checked_sub(1).and_then(|prev| chars.get(prev))) && emoji(chars.get(i + 1))
+    };
+    chars
+        .iter()
+        .enumerate()
+        .filter(|&(i, &c)| {
+            !c.is_control()
+                && !(invisible
+                    && matches!(
+                        c,
+                        '\u{00AD}'
+                            | '\u{200B}'..='\u{200F}'
+                            | '\u{202A}'..='\u{202E}'
+                            | '\u{2060}'..='\u{2064}'
+                            | '\u{2066}'..='\u{206F}'
+                            | '\u{FEFF}'
+                    )
+                    && !(c == '\u{200D}' && joins_emoji(i)))
+        })
+        .map(|(_, &c)| c)
+        .collect()
+}
+
+/// Friendly player name for a D-Bus service: a label from `player_names` if
+/// one matches the full service or its base name, then the player's
+/// `identity` if known, otherwise the base name
+/// (`org.mpris.MediaPlayer2.mpv.instance123` -> `mpv`).
+fn player_name(service: &str, identity: &str, player_names: &HashMap<String, String>) -> String {
+    let base = base_name(service);
+    player_names
+        .get(&service.to_lowercase())
+        .or_else(|| player_names.get(&base))
+        .cloned()
+        .or_else(|| Some(identity.to_string()).filter(|identity| !identity.is_empty()))
+        .unwrap_or(base)
+}
+
+/// Service name fragments of browsers, whose players are tabs.
+const BROWSERS: &[&str] = &["firefox", "librewolf", "chrom", "brave", "edge", "vivaldi", "opera"];
+
+fn is_browser(service: &str) -> bool {
+    let service = service.to_lowercase();
+    BROWSERS.iter().any(|browser| service.contains(browser))
+}
+
+/// Trailing title noise stripped by `--clean-title`. Only suffixes are matched so
+/// legitimate parentheses in the middle of a title are kept.
+static TITLE_NOISE: Lazy<Vec<Regex>> = Lazy::new(|| {
+    [
+        r"(?i)\s*[(\[][^()\[\]]*\b(remaster(ed)?|official|video|audio|lyrics?|visuali[sz]er|explicit|version|edit|mono|stereo|hd|hq|feat\.?|ft\.?|featuring)\b[^()\[\]]*[)\]]$",
+        r"(?i)\s+-\s+[^-]*\bremaster(ed)?\b[^-]*$",
+        r"(?i)\s+(feat\.|ft\.|featuring)\s+[^()\[\]]+$",
+    ]
+    .iter()
+    .map(|pattern| Regex::new(pattern).unwrap())
+    .collect()
+});
+
+/// Strip noise from a title using the built-in patterns (if enabled) and any
+/// user-supplied ones, repeating until nothing more matches.
+fn clean_title(title: &str, builtin: bool, extra: &[Regex]) -> String {
+    let builtin: &[Regex] = if builtin { &TITLE_NOISE } else { &[] };
+    let mut cleaned = title.trim().to_string();
+    loop {
+        let next = builtin
+            .iter()
+            .chain(extra)
+            .fold(cleaned.clone(), |text, re| re.replace(&text, "").trim().to_string());
+        if next == cleaned || next.is_empty() {
+            return cleaned;
+        }
+        cleaned = next;
+    }
+}
+
+/// Remove the first matching literal prefix and suffix from `title`, like a
+/// browser's ` - YouTube`. Leaves the title alone if nothing would remain.
+fn strip_affixes(title: &str, prefixes: &[String], suffixes: &[String]) -> String {
+    let mut stripped = title;
+    if let Some(rest) = prefixes.iter().find_map(|prefix| stripped.strip_prefix(prefix.as_str())) {
+        stripped = rest;
+    }
+    if let Some(rest) = suffixes.iter().find_map(|suffix| stripped.strip_suffix(suffix.as_str())) {
+        stripped = rest;
+    }
+    match stripped.trim() {
+        "" => title.to_string(),
+        stripped => stripped.to_string(),
+    }
+}
+
+/// Icon map keys with a special meaning, never matched against service names.
+const RESERVED_ICON_KEYS: &[&str] = &["404", "live", "play", "pause"];
+
+/// Status glyphs used unless `--play-icon`/`--pause-icon` or the `play`/`pause`
+/// icon keys override them.
+const DEFAULT_PLAY_ICON: &str = "";
+const DEFAULT_PAUSE_ICON: &str = "";
+
+/// Service icon followed by the play/pause status icon. `no_icon` drops both,
+/// `no_status_icon` keeps only the service icon. With `icon_colors` (markup
+/// on) a service icon that has a color is wrapped in a colored span.
 fn get_icon(
     player_state: &PlayerState,
     icon_format: &HashMap<String, String>,
-    no_play_icon: bool,
+    icon_colors: Option<&HashMap<String, String>>,
+    no_icon: bool,
+    no_status_icon: bool,
 ) -> String {
+    if no_icon {
+        return String::new();
+    }
     let service = player_state.get_service().unwrap_or("").to_lowercase();
+    let desktop_entry = player_state.desktop_entry.to_lowercase();
+    let identity = player_state.identity.to_lowercase();
+    let icon_for = |name: &str| {
+        icon_format
+            .iter()
+            .find(|(key, _)| !RESERVED_ICON_KEYS.contains(&key.as_str()) && name.contains(*key))
+            .map(|(key, icon)| (key.as_str(), icon.as_str()))
+    };
 
-    let service_icon = icon_format
-        .iter()
-        .find(|(key, _)| service.contains(*key))
-        .map(|(_, icon)| icon.as_str())
+    // The DesktopEntry and Identity are tried first, falling back to the service name.
+    let (service_key, service_icon) = [desktop_entry.as_str(), identity.as_str()]
+        .into_iter()
+        .filter(|name| !name.is_empty())
+        .find_map(icon_for)
+        .or_else(|| icon_for(&service))
         .unwrap_or_else(|| {
             icon_format
-                .get("404")
-                .map(|s| s.as_str())
-                .unwrap_or("")
+                .get_key_value("404")
+                .map(|(key, icon)| (key.as_str(), icon.as_str()))
+                .unwrap_or(("404", ""))
         });
+    let service_icon = match icon_colors.and_then(|colors| colors.get(service_key)) {
+        Some(color) if !service_icon.is_empty() => format!("<span color=\"{}\">{}</span>", color, service_icon),
+        _ => service_icon.to_string(),
+    };
 
-    let play_icon = if no_play_icon {
+    // Streams have no length; show the "live" glyph instead of play if one is set.
+    let live_icon = icon_format.get("live").filter(|_| player_state.length.is_none());
+    let play_icon = if no_status_icon {
         ""
+    } else if player_state.playing && let Some(live_icon) = live_icon {
+        live_icon.as_str()
     } else if player_state.playing {
-        ""
+        icon_format.get("play").map_or(DEFAULT_PLAY_ICON, String::as_str)
     } else {
-        ""
+        icon_format.get("pause").map_or(DEFAULT_PAUSE_ICON, String::as_str)
     };
 
-    if !service_icon.is_empty() {
-        format!("{} {}", service_icon, play_icon)
-    } else {
-        play_icon.to_string()
+    match (service_icon.is_empty(), play_icon.is_empty()) {
+        (false, false) => format!("{} {}", service_icon, play_icon),
+        (false, true) => service_icon.to_string(),
+        _ => play_icon.to_string(),
     }
 }
 
@@ -51,21 +370,42 @@ fn get_scrolled_text(
     player_state: &PlayerState,
     scroll_state: &mut ScrollState,
     formatted_metadata: &str,
+    advance_scroll: bool,
+    width: usize,
 ) -> String {
+    // A new track always scrolls from the start, even if its text is the same.
+    if scroll_state.generation != player_state.generation {
+        scroll_state.restart(player_state.generation);
+    }
+    if scroll_state.saved.is_some() && player_state.playing {
+        scroll_state.resume(&player_state.track_key(), formatted_metadata);
+    }
     if config.freeze_on_pause && !player_state.playing {
-        scroll_state.offset = 0;
-        scroll_state.hold = 0;
-        formatted_metadata.chars().take(config.width).collect()
+        // Either way the offset is kept, so resuming continues where
+        // scrolling left off.
+        match config.freeze_mode {
+            FreezeMode::InPlace if scroll_state.last_text == formatted_metadata => scroll_state.last_frame.clone(),
+            _ => formatted_metadata.graphemes(true).take(width).collect(),
+        }
+    } else if !advance_scroll && scroll_state.last_text == formatted_metadata {
+        // Redraw the frame on screen without moving the text.
+        scroll_state.last_frame.clone()
     } else {
-        scroll(
-            formatted_metadata,
-            scroll_state,
-            config.width,
-            match config.scroll_mode {
+        let options = ScrollOptions {
+            width,
+            mode: match config.scroll_mode {
                 ConfigScrollMode::Wrapping => ScrollMode::Wrapping,
                 ConfigScrollMode::Reset => ScrollMode::Reset,
             },
-        )
+            wrap_start_hold: config.wrap_start_hold,
+            gap: config.wrap_gap,
+            reset_hold: config.reset_hold,
+            padding: config.scroll_padding,
+            step: config.scroll_step,
+        };
+        let frame = scroll(formatted_metadata, scroll_state, &options);
+        scroll_state.last_frame = frame.clone();
+        frame
     }
 }
 
@@ -73,111 +413,999 @@ fn get_position_text(config: &Config, player_state: &PlayerState) -> String {
     if !config.position_enabled {
         return String::new();
     }
+    let shown = match config.position_when {
+        PositionWhen::Always => true,
+        PositionWhen::Paused => !player_state.playing,
+        PositionWhen::Playing => player_state.playing,
+    };
+    if !shown {
+        return String::new();
+    }
 
-    let seconds = match config.position_mode {
-        PositionMode::Increasing => player_state.estimate_position(),
-        PositionMode::Remaining => player_state
-            .length
-            .map_or(player_state.estimate_position(), |length| {
-                (length - player_state.estimate_position()).max(0.0)
-            }),
+    let template = config.position_format.as_deref();
+    let precision = config.position_precision;
+    let pad_hours = !config.position_no_pad_hours;
+    let elapsed = player_state.estimate_position();
+    let mut pos_text = match (config.position_mode, player_state.length) {
+        // A stream's elapsed time is meaningless, so show the live marker.
+        (_, None) if player_state.playing => config.live_text.clone(),
+        (PositionMode::Remaining, Some(length)) => {
+            format_position(length.saturating_sub(elapsed), template, precision, pad_hours)
+        }
+        (PositionMode::Full, Some(length)) => format!(
+            "{}/{}",
+            format_position(elapsed, template, precision, pad_hours),
+            format_position(length, template, precision, pad_hours)
+        ),
+        (PositionMode::FineBar, Some(length)) => {
+            let progress = if length.is_zero() { 0.0 } else { elapsed.as_secs_f64() / length.as_secs_f64() };
+            fine_bar(progress, config.bar_width)
+        }
+        _ => format_position(elapsed, template, precision, pad_hours),
     };
+    if player_state.position_from_seeks && !pos_text.is_empty() {
+        pos_text = format!("~{}", pos_text);
+    }
+    if config.markup
+        && let (Some(warn), Some(length)) = (config.position_warn_secs, player_state.length)
+        && length.saturating_sub(elapsed) < Duration::from_secs(warn)
+    {
+        pos_text = format!(
+            "<span color=\"{}\">{}</span>",
+            config.position_warn_color, pos_text
+        );
+    }
+    pos_text
+}
 
-    let pos_text = format_position(seconds);
-    if !pos_text.is_empty() {
-        format!(" {}", pos_text)
-    } else {
-        String::new()
+/// Partial block glyphs for one to seven eighths of a cell.
+const EIGHTH_BLOCKS: [char; 7] = ['▏', '▎', '▍', '▌', '▋', '▊', '▉'];
+
+/// A `cells` wide progress bar for `progress` (0 to 1): full blocks, then one
+/// partial block for the remaining eighths, padded with spaces.
+fn fine_bar(progress: f64, cells: usize) -> String {
+    let eighths = (progress.clamp(0.0, 1.0) * (cells * 8) as f64).round() as usize;
+    let mut bar = "█".repeat(eighths / 8);
+    match eighths % 8 {
+        0 => {}
+        partial => bar.push(EIGHTH_BLOCKS[partial - 1]),
     }
+    let used = eighths.div_ceil(8);
+    bar.push_str(&" ".repeat(cells - used));
+    bar
+}
+
+/// Split output into (unit, is_visible) pairs: grapheme clusters, so combining
+/// marks and emoji sequences are never cut apart, and, with markup, whole
+/// entities and tags.
+fn output_units(text: &str, markup: bool) -> Vec<(&str, bool)> {
+    let mut units = Vec::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let len = match c {
+            '<' | '&' if markup => {
+                let end = if c == '<' { '>' } else { ';' };
+                rest.find(end).map_or(c.len_utf8(), |i| i + 1)
+            }
+            _ => rest.graphemes(true).next().map_or(c.len_utf8(), str::len),
+        };
+        units.push((&rest[..len], !(markup && c == '<')));
+        rest = &rest[len..];
+    }
+    units
+}
+
+/// Cap the composed output at `max_length` display columns, ending with `…`
+/// when cut. With markup, tags don't count, entities count as one column and
+/// spans left open by the cut are closed.
+fn truncate_output(text: &str, max_length: usize, markup: bool) -> String {
+    if display_width(text, markup) <= max_length {
+        return text.to_string();
+    }
+    if max_length == 0 {
+        return String::new();
+    }
+
+    let mut out = String::new();
+    let mut visible = 0;
+    let mut open_spans = 0usize;
+    for (unit, is_visible) in output_units(text, markup) {
+        if is_visible {
+            // Leave a column for the `…`.
+            let width = unit_width(unit, markup);
+            if visible + width >= max_length {
+                break;
+            }
+            visible += width;
+        } else if unit.starts_with("</") {
+            open_spans = open_spans.saturating_sub(1);
+        } else {
+            open_spans += 1;
+        }
+        out.push_str(unit);
+    }
+    out.push('…');
+    out.push_str(&"</span>".repeat(open_spans));
+    out
+}
+
+/// Terminal-style display width of the output: wide characters count as
+/// two columns, tags as none and entities as one.
+fn display_width(text: &str, markup: bool) -> usize {
+    output_units(text, markup)
+        .into_iter()
+        .filter(|(_, visible)| *visible)
+        .map(|(unit, _)| unit_width(unit, markup))
+        .sum()
 }
 
-/// Print status for the current player, only if output changes.
-pub fn print_status(
+/// Columns a visible unit of `output_units` takes.
+fn unit_width(unit: &str, markup: bool) -> usize {
+    if markup && unit.starts_with('&') { 1 } else { unit.width() }
+}
+
+/// Pad the output with spaces to at least `min_width` columns.
+fn pad_output(text: &str, min_width: usize, align: Align, markup: bool) -> String {
+    let padding = min_width.saturating_sub(display_width(text, markup));
+    let left = match align {
+        Align::Left => 0,
+        Align::Center => padding / 2,
+        Align::Right => padding,
+    };
+    format!("{}{}{}", " ".repeat(left), text, " ".repeat(padding - left))
+}
+
+/// Escape text for Pango markup.
+fn escape_markup(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Output for a stopped player: blank, or `--format-stopped` filled in from
+/// the last track played.
+fn stopped_line(config: &Config, player_state: &PlayerState) -> StatusLine {
+    let mut line = StatusLine::blank(config, "stopped", None);
+    if let Some(format) = &config.format_stopped
+        && let Some(track) = &player_state.last_played
+    {
+        let sanitized = |text: &str| if config.no_sanitize { text.to_string() } else { sanitize(text, config.strip_invisible) };
+        let (title, artist, album) = (sanitized(&track.title), sanitized(&track.artist), sanitized(&track.album));
+        let year = track.year.map(|year| year.to_string()).unwrap_or_default();
+        let player = player_name(&player_state.last_played_service, "", &config.player_names);
+        let fields = [
+            ("title", title.as_str()),
+            ("artist", artist.as_str()),
+            ("album", album.as_str()),
+            ("player", player.as_str()),
+            ("year", year.as_str()),
+        ];
+        let text = apply_case(&format_metadata(format, &fields, config.dedupe_fields), config.case);
+        line.text = if config.markup { escape_markup(&text) } else { text };
+    }
+    line
+}
+
+/// Shorten `text` in step with the time left once fewer than `fade` remain in
+/// the track, so it has faded out by the end (--fade-out). Streams without a
+/// length are left alone.
+fn fade_out(text: &str, player_state: &PlayerState, fade: Duration) -> String {
+    let Some(length) = player_state.length else {
+        return text.to_string();
+    };
+    let left = length.saturating_sub(player_state.estimate_position());
+    if left >= fade {
+        return text.to_string();
+    }
+    let graphemes = text.graphemes(true).count();
+    let keep = (graphemes as f64 * left.as_secs_f64() / fade.as_secs_f64()).ceil() as usize;
+    text.graphemes(true).take(keep).collect()
+}
+
+/// How much wider than --width the text may get while peeking.
+const PEEK_WIDTH_FACTOR: usize = 3;
+
+/// The whole text for --peek-on-change, cut with `…` only past `cap` characters.
+fn peek_text(text: &str, cap: usize) -> String {
+    if text.graphemes(true).count() <= cap {
+        return text.to_string();
+    }
+    let mut cut: String = text.graphemes(true).take(cap.saturating_sub(1)).collect();
+    cut.push('…');
+    cut
+}
+
+/// Whether the tick loop renders `player_state`: while playing, including a
+/// pause still held back by --status-debounce, so scrolling and the clock
+/// carry on through it.
+pub fn ticks(config: &Config, player_state: &PlayerState) -> bool {
+    // A paused player is re-checked so --pause-timeout can blank it.
+    let pause_pending = config.pause_timeout > 0 && player_state.paused_since.is_some();
+    // Likewise a paused player still flashing or peeking, so that ends on time.
+    let flash_pending = (config.flash_on_change || config.peek_on_change > 0) && player_state.track_changed_at.is_some();
+    player_state.playing || player_state.pending_pause.is_some() || pause_pending || flash_pending
+}
+
+/// Render the status line for the current player. Writing it is left to the
+/// caller, so that can happen after the state lock is released.
+///
+/// With `advance_scroll` false the current scroll frame is redrawn as is,
+/// e.g. to refresh the position.
+pub fn render_status(
     config: &Config,
     player_state: &mut PlayerState,
     scroll_state: &mut ScrollState,
-    last_output: &mut String,
-) {
+    advance_scroll: bool,
+) -> String {
     // If there's no metadata, output a stopped status.
     if player_state.title.is_empty()
         && player_state.artist.is_empty()
         && player_state.album.is_empty()
     {
-        let json_output = serde_json::json!({
-            "text": "",
-            "class": "stopped",
-        })
-        .to_string();
+        return match &player_state.err {
+            Some(err) => emit(config, StatusLine::blank(config, "error", Some(err.clone()))),
+            None => emit_stopped(config, stopped_line(config, player_state)),
+        };
+    }
 
-        if *last_output != json_output {
-            println!("{}", json_output);
-            *last_output = json_output;
-        }
-        return;
+    if config.format_stopped.is_some() && player_state.status == "Stopped" {
+        return emit_stopped(config, stopped_line(config, player_state));
+    }
+
+    if config.pause_timeout > 0
+        && player_state
+            .paused_since
+            .is_some_and(|since| since.elapsed().as_secs() >= config.pause_timeout)
+    {
+        return emit_stopped(config, StatusLine::blank(config, "stopped", None));
     }
 
-    let formatted = format_metadata(
-        &config.format,
-        &player_state.title,
-        &player_state.artist,
-        &player_state.album,
-    );
+    // A player that loaded a track but was never started sits paused at 0.
+    if config.paused_zero_as_stopped
+        && player_state.status == "Paused"
+        && !player_state.ever_played
+        && player_state.estimate_position() < Duration::from_secs(1)
+    {
+        return emit_stopped(config, StatusLine::blank(config, "stopped", None));
+    }
+
+    if config.inhibit_when_fullscreen && player_state.fullscreen {
+        return emit(config, StatusLine::blank(config, "fullscreen", None));
+    }
+
+    let title = strip_affixes(&player_state.title, &config.strip_prefix, &config.strip_suffix);
+    let title = clean_title(&title, config.clean_title, &config.clean_title_patterns);
+
+    let service = player_state.get_service().unwrap_or("");
+    let player = player_name(service, &player_state.identity, &config.player_names);
+    let app = Some(player_state.desktop_entry.clone())
+        .filter(|entry| !entry.is_empty())
+        .unwrap_or_else(|| base_name(service));
+
+    // Browser tabs usually end their title with " - Site"; that becomes
+    // {player} so tabs can be told apart.
+    let browser_label = config.browser_label.as_deref().filter(|_| is_browser(service));
+    let (title, player) = match title.rsplit_once(" - ") {
+        Some((rest, site)) if browser_label.is_some() && config.browser_site && !rest.trim().is_empty() => {
+            (rest.trim().to_string(), site.trim().to_string())
+        }
+        _ => (title, player),
+    };
+
+    let format = match &config.format_paused {
+        Some(format_paused) if !player_state.playing => format_paused,
+        _ if !config.cycle_formats.is_empty() => {
+            &config.cycle_formats[scroll_state.format_index % config.cycle_formats.len()]
+        }
+        _ => &config.format,
+    };
 
-    let scrolled_text = get_scrolled_text(config, player_state, scroll_state, &formatted);
+    let sanitized = |text: &str| if config.no_sanitize { text.to_string() } else { sanitize(text, config.strip_invisible) };
+    let mut title = sanitized(&title);
+    let mut artist = sanitized(&player_state.artist);
+    let mut album = sanitized(&player_state.album);
+    let next_title = sanitized(&player_state.next_title);
+    let year = player_state.year.map(|year| year.to_string()).unwrap_or_default();
+    // Untitled tracks (often podcasts) borrow the first non-empty fallback
+    // field as their title; it moves rather than showing up twice.
+    if title.trim().is_empty() {
+        for fallback in &config.title_fallback {
+            let field = match fallback.as_str() {
+                "album" => &mut album,
+                "artist" => &mut artist,
+                _ => continue,
+            };
+            if !field.trim().is_empty() {
+                title = std::mem::take(field);
+                break;
+            }
+        }
+    }
+    let fields = [
+        ("title", title.as_str()),
+        ("artist", artist.as_str()),
+        ("album", album.as_str()),
+        ("player", player.as_str()),
+        ("app", app.as_str()),
+        ("next_title", next_title.as_str()),
+        ("year", year.as_str()),
+    ];
+    let split = config
+        .scroll_field
+        .as_deref()
+        .and_then(|field| split_scroll_field(format, &fields, field, config.dedupe_fields));
+    // Right after a track change the new text can be shown whole for a moment.
+    let since_change = player_state.track_changed_at.map(|at| at.elapsed());
+    let peeking = since_change.is_some_and(|elapsed| elapsed < Duration::from_secs(config.peek_on_change));
+    let peek_cap = config.width * PEEK_WIDTH_FACTOR;
+    // The browser label and its space come out of --width.
+    let text_width = match browser_label {
+        Some(label) if config.width > 0 => config.width.saturating_sub(label.width() + 1).max(1),
+        _ => config.width,
+    };
+    // --width 0 leaves just the icons and position.
+    let mut scrolled_text = match split {
+        _ if config.width == 0 => String::new(),
+        Some((prefix, value, suffix)) if peeking => {
+            let text = format!("{}{}{}", prefix, value, suffix);
+            peek_text(&apply_case(&text, config.case), peek_cap)
+        }
+        // Only the chosen field scrolls, in whatever width the static text leaves.
+        Some((prefix, value, suffix)) => {
+            let prefix = apply_case(&prefix, config.case);
+            let suffix = apply_case(&suffix, config.case);
+            let width = text_width
+                .saturating_sub(prefix.graphemes(true).count() + suffix.graphemes(true).count())
+                .max(1);
+            let value = apply_case(&value, config.case);
+            let frame = get_scrolled_text(config, player_state, scroll_state, &value, advance_scroll, width);
+            format!("{}{}{}", prefix, frame, suffix)
+        }
+        None => {
+            let formatted = if config.compact {
+                compact_metadata(format, &fields, &config.compact_order, text_width, config.dedupe_fields)
+            } else {
+                format_metadata(format, &fields, config.dedupe_fields)
+            };
+            let formatted = apply_case(&formatted, config.case);
+            if peeking {
+                peek_text(&formatted, peek_cap)
+            } else {
+                get_scrolled_text(config, player_state, scroll_state, &formatted, advance_scroll, text_width)
+            }
+        }
+    };
+    if config.fade_out > 0 {
+        scrolled_text = fade_out(&scrolled_text, player_state, Duration::from_secs(config.fade_out));
+    }
+    if config.markup {
+        scrolled_text = escape_markup(&scrolled_text);
+    }
+    if let Some(label) = browser_label
+        && !scrolled_text.is_empty()
+    {
+        let label = if config.markup { escape_markup(label) } else { label.to_string() };
+        scrolled_text = format!("{} {}", label, scrolled_text);
+    }
 
     // This check is still useful if formatted metadata results in an empty scrolled_text
     // even if title/artist/album are not all empty (e.g., format string is empty).
-    if scrolled_text.trim().is_empty() {
-        if !last_output.is_empty() {
-            println!();
-            *last_output = String::new();
-        }
-        return;
+    // A playing player with position enabled still shows its clock.
+    let text_empty = scrolled_text.trim().is_empty();
+    if text_empty && config.width > 0 && !(config.position_enabled && player_state.playing) {
+        return String::new();
     }
 
     let class = &player_state.status.to_lowercase();
     let position_text = get_position_text(config, player_state);
+    // The delimiter only goes between two non-empty sides.
+    let body = if text_empty {
+        position_text
+    } else if position_text.is_empty() {
+        scrolled_text
+    } else {
+        format!("{}{}{}", scrolled_text, config.position_delimiter, position_text)
+    };
 
-    let output = if class == &"stopped".to_string() {
+    let icon_colors = config.markup.then_some(&config.icon_colors);
+    let icon = get_icon(player_state, &config.icon_format, icon_colors, config.no_icon, config.no_status_icon);
+    let stopped = class == "stopped";
+    let output = if stopped {
         String::new()
-    } else if config.no_icon {
-        format!("{}{}", scrolled_text, position_text)
+    } else if icon.is_empty() {
+        body
+    } else if body.is_empty() {
+        icon
+    } else if config.icon_position == IconPosition::Right {
+        format!("{}{}{}", body, config.icon_separator, icon)
     } else {
-        let icon = get_icon(player_state, &config.icon_format, config.no_status_icon);
-        format!("{} {}{}", icon, scrolled_text, position_text)
-    };
-
-    let tooltip = format_metadata(
-        &config.tooltip_format,
-        &player_state.title,
-        &player_state.artist,
-        &player_state.album,
-    );
-
-    let json_output: String = serde_json::json!({
-        "text": output,
-        "class": class,
-        "tooltip": tooltip
-    })
-    .to_string();
-
-    if *last_output != json_output {
-        println!("{}", json_output);
-        *last_output = json_output;
-    }
-}
-
-/// Formats time (in seconds) to a mm:ss or hh:mm:ss string.
-pub fn format_position(seconds: f64) -> String {
-    let total_seconds = seconds as i64;
-    if total_seconds >= 3600 {
-        let hours = total_seconds / 3600;
-        let minutes = (total_seconds % 3600) / 60;
-        let seconds = total_seconds % 60;
-        format!("{:02}:{:02}:{:02}", hours, minutes, seconds)
+        format!("{}{}{}", icon, config.icon_separator, body)
+    };
+
+    let output = match config.max_length {
+        Some(max_length) => truncate_output(&output, max_length, config.markup),
+        None => output,
+    };
+    let output = match config.min_width {
+        Some(min_width) => pad_output(&output, min_width, config.align, config.markup),
+        None => output,
+    };
+
+    let mut tooltip = format_metadata(&config.tooltip_format, &fields, config.dedupe_fields);
+    if config.tooltip_position {
+        tooltip = format!("{}\n{}", tooltip, tooltip_position(config, player_state));
+    }
+    if config.markup {
+        tooltip = escape_markup(&tooltip);
+    }
+
+    // A failed D-Bus fetch takes over the class and tooltip until the next
+    // successful update clears it.
+    let class = match &player_state.err {
+        Some(err) => {
+            tooltip = if config.markup { escape_markup(err) } else { err.clone() };
+            "error".to_string()
+        }
+        None => class.to_string(),
+    };
+
+    let mut classes = vec![class];
+    if config.json_class_array && player_state.playing && player_state.length.is_none() {
+        classes.push("live".to_string());
+    }
+    // Added briefly after a track change.
+    let flashing = since_change.is_some_and(|elapsed| elapsed < TRACK_CHANGE_FLASH);
+    if !flashing && !peeking {
+        player_state.track_changed_at = None;
+    }
+    if config.flash_on_change && flashing {
+        classes.push("track-changed".to_string());
+    }
+
+    // Progress for Waybar's own bar; left out for streams without a length.
+    let percentage = player_state
+        .length
+        .filter(|length| (config.position_enabled || config.percentage) && !length.is_zero())
+        .map(|length| {
+            let progress = player_state.estimate_position().as_secs_f64() / length.as_secs_f64();
+            (progress * 100.0).clamp(0.0, 100.0) as u8
+        });
+
+    let output = StatusLine {
+        text: output,
+        class: Class::new(classes, config.json_class_array),
+        // Nothing to hover over while stopped.
+        tooltip: (!stopped).then_some(tooltip),
+        percentage,
+    };
+    if stopped {
+        emit_stopped(config, output)
     } else {
-        let minutes = total_seconds / 60;
-        let seconds = total_seconds % 60;
-        format!("{:02}:{:02}", minutes, seconds)
+        emit(config, output)
+    }
+}
+
+/// The `elapsed / length` line --tooltip-position adds, just the elapsed
+/// time for streams.
+fn tooltip_position(config: &Config, player_state: &PlayerState) -> String {
+    let format = |time| {
+        format_position(time, config.position_format.as_deref(), config.position_precision, !config.position_no_pad_hours)
+    };
+    let elapsed = format(player_state.estimate_position());
+    match player_state.length {
+        Some(length) => format!("{} / {}", elapsed, format(length)),
+        None => elapsed,
+    }
+}
+
+/// One line of output, serialized as Waybar's custom module JSON.
+#[derive(Serialize)]
+struct StatusLine {
+    text: String,
+    class: Class,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tooltip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    percentage: Option<u8>,
+}
+
+impl StatusLine {
+    /// An empty line carrying only a class (and maybe a tooltip).
+    fn blank(config: &Config, class: &str, tooltip: Option<String>) -> Self {
+        Self {
+            text: String::new(),
+            class: Class::new(vec![class.to_string()], config.json_class_array),
+            tooltip,
+            percentage: None,
+        }
+    }
+}
+
+/// Waybar takes `class` as a single string or a list of strings.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Class {
+    Single(String),
+    List(Vec<String>),
+}
+
+impl Class {
+    fn new(mut classes: Vec<String>, as_list: bool) -> Self {
+        if as_list || classes.len() > 1 {
+            Class::List(classes)
+        } else {
+            Class::Single(classes.pop().unwrap_or_default())
+        }
+    }
+}
+
+/// Like `emit`, but with --hide-when-stopped an empty stopped line becomes a
+/// bare empty line. Unchanged lines aren't written again, so that prints
+/// nothing at all, except once to clear what was shown before.
+fn emit_stopped(config: &Config, output: StatusLine) -> String {
+    if config.hide_when_stopped && output.text.is_empty() {
+        return String::new();
+    }
+    emit(config, output)
+}
+
+/// The output line in the configured format.
+fn emit(config: &Config, output: StatusLine) -> String {
+    match config.output_format {
+        OutputFormat::Waybar => serde_json::to_string(&output).unwrap_or_default(),
+        OutputFormat::Plain => output.text,
+    }
+}
+
+/// Tokens accepted in a position template after `%`.
+const POSITION_TOKENS: &[char] = &['H', 'h', 'M', 'm', 'S', 's', 'f', '%'];
+
+/// Unknown `%` tokens in a position template.
+pub fn unknown_position_tokens(template: &str) -> Vec<String> {
+    let mut unknown = Vec::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c == '%' {
+            match chars.next() {
+                Some(token) if POSITION_TOKENS.contains(&token) => {}
+                Some(token) => unknown.push(format!("%{}", token)),
+                None => unknown.push("%".to_string()),
+            }
+        }
+    }
+    unknown
+}
+
+/// Formats a position or length with a template, or to a mm:ss or hh:mm:ss
+/// string by default.
+///
+/// `%H`, `%M`, `%S` are zero-padded hours, minutes and seconds, `%h`, `%m`,
+/// `%s` are unpadded, `%f` is zero-padded milliseconds and `%%` is a literal
+/// `%`. Without an hours token, minutes count the full duration. The default
+/// template shows milliseconds with `PositionPrecision::Ms`, and its hours are
+/// only zero-padded with `pad_hours`.
+pub fn format_position(position: Duration, template: Option<&str>, precision: PositionPrecision, pad_hours: bool) -> String {
+    let total_seconds = position.as_secs();
+    let millis = position.subsec_millis();
+    let template = template.unwrap_or(match (total_seconds >= 3600, precision, pad_hours) {
+        (true, PositionPrecision::S, true) => "%H:%M:%S",
+        (true, PositionPrecision::S, false) => "%h:%M:%S",
+        (false, PositionPrecision::S, _) => "%M:%S",
+        (true, PositionPrecision::Ms, true) => "%H:%M:%S.%f",
+        (true, PositionPrecision::Ms, false) => "%h:%M:%S.%f",
+        (false, PositionPrecision::Ms, _) => "%M:%S.%f",
+    });
+    let hours = total_seconds / 3600;
+    let minutes = if template.contains("%H") || template.contains("%h") {
+        (total_seconds % 3600) / 60
+    } else {
+        total_seconds / 60
+    };
+    let seconds = total_seconds % 60;
+
+    let mut out = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('H') => out.push_str(&format!("{:02}", hours)),
+            Some('h') => out.push_str(&hours.to_string()),
+            Some('M') => out.push_str(&format!("{:02}", minutes)),
+            Some('m') => out.push_str(&minutes.to_string()),
+            Some('S') => out.push_str(&format!("{:02}", seconds)),
+            Some('s') => out.push_str(&seconds.to_string()),
+            Some('f') => out.push_str(&format!("{:03}", millis)),
+            Some('%') => out.push('%'),
+            Some(other) => {
+                out.push('%');
+                out.push(other);
+            }
+            None => out.push('%'),
+        }
+    }
+    out
+}
+
+/// Per-user directory for runtime files: `$XDG_RUNTIME_DIR/scrollmpris`, or
+/// `/tmp/scrollmpris/<uid>` when it isn't set. Created with 0700 permissions.
+pub fn runtime_dir() -> io::Result<PathBuf> {
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(xdg) if !xdg.is_empty() => PathBuf::from(xdg).join("scrollmpris"),
+        _ => {
+            // The shared parent is sticky and world-writable like /tmp itself,
+            // so every user can create their own subdirectory.
+            let parent = PathBuf::from("/tmp/scrollmpris");
+            match fs::create_dir(&parent) {
+                Ok(()) => fs::set_permissions(&parent, fs::Permissions::from_mode(0o1777))?,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+            parent.join(unsafe { libc::getuid() }.to_string())
+        }
+    };
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+    Ok(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use super::{
+        compact_metadata, fade_out, fine_bar, format_metadata, format_position, get_icon, pad_output, render_status,
+        sanitize, strip_affixes, ticks, truncate_output,
+    };
+    use crate::config::{Align, Config, PositionPrecision};
+    use crate::mpris::metadata::micros_to_duration;
+    use crate::player::PlayerState;
+    use crate::scroll::ScrollState;
+
+    const FIELDS: [(&str, &str); 3] = [("title", "Song"), ("artist", "Band"), ("album", "Record")];
+
+    fn without<'a>(missing: &str) -> Vec<(&'a str, &'a str)> {
+        FIELDS.iter().map(|&(k, v)| if k == missing { (k, "") } else { (k, v) }).collect()
+    }
+
+    /// A playing player on `service` with the given title.
+    fn playing(service: &str, title: &str) -> PlayerState {
+        let mut player_state = PlayerState {
+            title: title.to_string(),
+            artist: "Band".to_string(),
+            ..Default::default()
+        };
+        player_state.set_service(service);
+        player_state.update_playback_dbus("Playing".to_string(), Some(Duration::ZERO));
+        player_state
+    }
+
+    /// The plain text of one render with `args`.
+    fn render(args: &[&str], player_state: &mut PlayerState) -> String {
+        let config = Config::from_args(&[&["--output-format", "plain", "--no-icon"], args].concat());
+        render_status(&config, player_state, &mut ScrollState::new(), false)
+    }
+
+    /// The Waybar JSON of one render with `args`.
+    fn render_json(args: &[&str], player_state: &mut PlayerState) -> serde_json::Value {
+        let config = Config::from_args(&[&["--no-icon"], args].concat());
+        serde_json::from_str(&render_status(&config, player_state, &mut ScrollState::new(), false)).unwrap()
+    }
+
+    #[test]
+    fn all_fields_present() {
+        assert_eq!(format_metadata("{artist} - {title} ({album})", &FIELDS, false), "Band - Song (Record)");
+    }
+
+    #[test]
+    fn missing_artist_drops_separator() {
+        assert_eq!(format_metadata("{artist} - {title}", &without("artist"), false), "Song");
+    }
+
+    #[test]
+    fn missing_title_drops_separator() {
+        assert_eq!(format_metadata("{artist} - {title}", &without("title"), false), "Band");
+        assert_eq!(format_metadata("{artist} - {album} - {title}", &without("album"), false), "Band - Song");
+    }
+
+    #[test]
+    fn empty_format_is_empty() {
+        assert_eq!(format_metadata("", &FIELDS, false), "");
+    }
+
+    #[test]
+    fn prefix_and_suffix_are_kept() {
+        assert_eq!(format_metadata("[{title}]", &FIELDS, false), "[Song]");
+        assert_eq!(format_metadata("♪ {artist} - {title} ♪", &without("artist"), false), "♪ Song ♪");
+    }
+
+    #[test]
+    fn brackets_around_a_missing_field_go_with_it() {
+        assert_eq!(format_metadata("{title} ({album})", &FIELDS, false), "Song (Record)");
+        assert_eq!(format_metadata("{title} ({album})", &without("album"), false), "Song");
+        assert_eq!(format_metadata("[{album}] {title}", &without("album"), false), "Song");
+        assert_eq!(format_metadata("{artist} - {title} [{album}]!", &without("album"), false), "Band - Song!");
+        // Brackets around the whole text stay.
+        assert_eq!(format_metadata("({artist} - {title})", &without("artist"), false), "(Song)");
+    }
+
+    #[test]
+    fn consecutive_placeholders() {
+        assert_eq!(format_metadata("{artist}{title}", &FIELDS, false), "BandSong");
+        assert_eq!(format_metadata("{artist}{title}", &without("artist"), false), "Song");
+    }
+
+    #[test]
+    fn dedupe_drops_repeated_field_and_separator() {
+        let fields = [("title", "Podcast"), ("artist", " Podcast "), ("album", "Episode 3")];
+        assert_eq!(format_metadata("{title} - {artist} | {album}", &fields, true), "Podcast | Episode 3");
+        assert_eq!(format_metadata("{title} - {artist} | {album}", &fields, false), "Podcast - Podcast | Episode 3");
+    }
+
+    #[test]
+    fn unknown_placeholders_are_literal() {
+        assert_eq!(format_metadata("{title} {foo}", &FIELDS, false), "Song {foo}");
+    }
+
+    #[test]
+    fn icon_respects_no_icon_and_no_status_icon() {
+        let icons: HashMap<String, String> =
+            [("spotify", "S"), ("404", ""), ("play", "P"), ("pause", "W")].map(|(k, v)| (k.to_string(), v.to_string())).into();
+        let mut player_state = PlayerState { playing: true, length: Some(Duration::from_secs(180)), ..Default::default() };
+        player_state.set_service("org.mpris.MediaPlayer2.spotify");
+
+        assert_eq!(get_icon(&player_state, &icons, None, false, false), "S P");
+        assert_eq!(get_icon(&player_state, &icons, None, false, true), "S");
+        assert_eq!(get_icon(&player_state, &icons, None, true, false), "");
+        assert_eq!(get_icon(&player_state, &icons, None, true, true), "");
+
+        // With no service icon (an empty 404) only the status icon is left.
+        player_state.set_service("org.mpris.MediaPlayer2.mpv");
+        assert_eq!(get_icon(&player_state, &icons, None, false, false), "P");
+        assert_eq!(get_icon(&player_state, &icons, None, false, true), "");
+    }
+
+    #[test]
+    fn compact_drops_fields_in_order_until_it_fits() {
+        let format = "{artist} - {title} | {album}";
+        let order = ["album".to_string(), "artist".to_string()];
+        assert_eq!(compact_metadata(format, &FIELDS, &order, 40, false), "Band - Song | Record");
+        assert_eq!(compact_metadata(format, &FIELDS, &order, 15, false), "Band - Song");
+        assert_eq!(compact_metadata(format, &FIELDS, &order, 8, false), "Song");
+        // Still too long once everything droppable is gone; left for the scroller.
+        assert_eq!(compact_metadata(format, &FIELDS, &order, 2, false), "Song");
+    }
+
+    #[test]
+    fn compact_follows_custom_order() {
+        let format = "{artist} - {title} | {album}";
+        let order = ["artist".to_string(), "album".to_string()];
+        assert_eq!(compact_metadata(format, &FIELDS, &order, 15, false), "Song | Record");
+    }
+
+    #[test]
+    fn sanitize_strips_control_characters() {
+        assert_eq!(sanitize("Line\nBreak\t\u{1b}[31m", false), "LineBreak[31m");
+        assert_eq!(sanitize("Zero\u{200B}Width\u{FEFF}", false), "Zero\u{200B}Width\u{FEFF}");
+        assert_eq!(sanitize("Ünïcödé ♪", false), "Ünïcödé ♪");
+    }
+
+    #[test]
+    fn sanitize_strips_invisible_characters_when_asked() {
+        assert_eq!(sanitize("Zero\u{200B}Width\u{FEFF}", true), "ZeroWidth");
+        assert_eq!(sanitize("Join\u{200D}ed", true), "Joined");
+        // A joiner between emoji is part of the sequence.
+        assert_eq!(sanitize("Coder 👩\u{200D}💻", true), "Coder 👩\u{200D}💻");
+    }
+
+    #[test]
+    fn pad_counts_display_width() {
+        assert_eq!(pad_output("abc", 6, Align::Left, false), "abc   ");
+        assert_eq!(pad_output("日本", 6, Align::Left, false), "日本  ");
+        assert_eq!(pad_output("<b>a&amp;b</b>", 5, Align::Left, true), "<b>a&amp;b</b>  ");
+        assert_eq!(pad_output("longer", 3, Align::Left, false), "longer");
+    }
+
+    #[test]
+    fn pad_aligns_within_min_width() {
+        assert_eq!(pad_output("abc", 6, Align::Right, false), "   abc");
+        assert_eq!(pad_output("abc", 6, Align::Center, false), " abc  ");
+        assert_eq!(pad_output("abcd", 6, Align::Center, false), " abcd ");
+    }
+
+    #[test]
+    fn truncation_keeps_grapheme_clusters_whole() {
+        assert_eq!(truncate_output("Cafe\u{301} Tacuba", 5, false), "Cafe\u{301}…");
+        assert_eq!(truncate_output("Flag 🇯🇵 anthem", 8, false), "Flag 🇯🇵…");
+    }
+
+    #[test]
+    fn truncation_counts_display_columns() {
+        assert_eq!(truncate_output("日本語の歌", 6, false), "日本…");
+        assert_eq!(truncate_output("日本語の歌", 10, false), "日本語の歌");
+        assert_eq!(truncate_output("ab日本", 4, false), "ab…");
+        assert_eq!(
+            truncate_output("<span weight=\"bold\">日本語</span>", 5, true),
+            "<span weight=\"bold\">日本…</span>"
+        );
+    }
+
+    #[test]
+    fn negative_position_shows_zero() {
+        // Some players report a small negative Position after seeking to the start.
+        let mut player_state = PlayerState { length: Some(Duration::from_secs(180)), ..Default::default() };
+        player_state.update_playback_dbus("Paused".to_string(), Some(micros_to_duration(-250_000)));
+        assert_eq!(format_position(player_state.estimate_position(), None, PositionPrecision::S, true), "00:00");
+
+        player_state.reset_position_cache(micros_to_duration(i64::MIN));
+        assert_eq!(format_position(player_state.estimate_position(), None, PositionPrecision::S, true), "00:00");
+    }
+
+    #[test]
+    fn fade_out_shortens_text_near_the_end() {
+        let fade = Duration::from_secs(4);
+        let mut player_state = PlayerState { length: Some(Duration::from_secs(100)), ..Default::default() };
+        player_state.reset_position_cache(Duration::from_secs(50));
+        assert_eq!(fade_out("Song - Band", &player_state, fade), "Song - Band");
+        player_state.reset_position_cache(Duration::from_secs(98));
+        assert_eq!(fade_out("Song - Band", &player_state, fade), "Song -");
+        player_state.reset_position_cache(Duration::from_secs(100));
+        assert_eq!(fade_out("Song - Band", &player_state, fade), "");
+
+        player_state.length = None;
+        assert_eq!(fade_out("Song - Band", &player_state, fade), "Song - Band");
+    }
+
+    #[test]
+    fn fine_bar_maps_remainder_to_partial_block() {
+        assert_eq!(fine_bar(0.0, 4), "    ");
+        assert_eq!(fine_bar(0.5, 4), "██  ");
+        // 0.3 of 32 eighths rounds to 10: one full cell and a quarter.
+        assert_eq!(fine_bar(0.3, 4), "█▎  ");
+        assert_eq!(fine_bar(0.99, 4), "████");
+        assert_eq!(fine_bar(1.7, 4), "████");
+        assert_eq!(fine_bar(-1.0, 4), "    ");
+    }
+
+    #[test]
+    fn icon_color_applies_only_with_markup() {
+        let icons: HashMap<String, String> = [("spotify", "S"), ("play", "P")].map(|(k, v)| (k.to_string(), v.to_string())).into();
+        let colors: HashMap<String, String> = [("spotify".to_string(), "#1db954".to_string())].into();
+        let mut player_state = PlayerState { playing: true, length: Some(Duration::from_secs(180)), ..Default::default() };
+        player_state.set_service("org.mpris.MediaPlayer2.spotify");
+
+        assert_eq!(get_icon(&player_state, &icons, Some(&colors), false, false), "<span color=\"#1db954\">S</span> P");
+        assert_eq!(get_icon(&player_state, &icons, None, false, false), "S P");
+    }
+
+    #[test]
+    fn default_template_pads_hours_only_when_asked() {
+        let long = Duration::from_secs(5 * 3600 + 44 * 60 + 21);
+        assert_eq!(format_position(long, None, PositionPrecision::S, true), "05:44:21");
+        assert_eq!(format_position(long, None, PositionPrecision::S, false), "5:44:21");
+        assert_eq!(format_position(Duration::from_secs(65), None, PositionPrecision::S, false), "01:05");
+        // An explicit template decides for itself.
+        assert_eq!(format_position(long, Some("%H:%M"), PositionPrecision::S, false), "05:44");
+    }
+
+    #[test]
+    fn browser_label_is_escaped_and_fits_the_width() {
+        let service = "org.mpris.MediaPlayer2.firefox.instance_1_42";
+        let args = ["--browser-label", "A&B", "--markup", "--width", "10", "--format", "{title}"];
+        let text = render(&args, &mut playing(service, "Lecture on rivers"));
+        assert!(text.starts_with("A&amp;B "), "{}", text);
+        assert_eq!(super::display_width(&text, true), 10);
+        // Other players get neither the label nor the narrower width.
+        let text = render(&args, &mut playing("org.mpris.MediaPlayer2.mpv", "Lecture on rivers"));
+        assert_eq!(super::display_width(&text, true), 10);
+        assert!(!text.contains("A&amp;B"), "{}", text);
+    }
+
+    #[test]
+    fn paused_at_zero_is_stopped_only_until_played() {
+        let args = ["--paused-zero-as-stopped", "--format", "{title}"];
+        let mut player_state = PlayerState { title: "Song".to_string(), ..Default::default() };
+        player_state.set_service("org.mpris.MediaPlayer2.mpv");
+        player_state.update_playback_dbus("Paused".to_string(), Some(Duration::ZERO));
+        assert_eq!(render(&args, &mut player_state), "");
+
+        player_state.update_playback_dbus("Playing".to_string(), Some(Duration::ZERO));
+        player_state.update_playback_dbus("Paused".to_string(), Some(Duration::ZERO));
+        assert_eq!(render(&args, &mut player_state), "Song");
+
+        // Another player has to be seen playing again.
+        player_state.set_service("org.mpris.MediaPlayer2.vlc");
+        assert_eq!(render(&args, &mut player_state), "");
+    }
+
+    #[test]
+    fn position_delimiter_only_goes_between_text_and_position() {
+        let mut player_state = playing("org.mpris.MediaPlayer2.mpv", "Song");
+        player_state.length = Some(Duration::from_secs(200));
+        assert_eq!(render(&["--format", "{title}", "-p"], &mut player_state), "Song 00:00");
+        assert_eq!(render(&["--format", "{title}"], &mut player_state), "Song");
+        assert_eq!(render(&["--format", "{album}", "-p"], &mut player_state), "00:00");
+        let args = ["--format", "{title}", "-p", "--position-delimiter", " | "];
+        assert_eq!(render(&args, &mut player_state), "Song | 00:00");
+    }
+
+    #[test]
+    fn tooltip_position_is_left_out_when_stopped() {
+        let args = ["--format", "{title}", "--tooltip-position"];
+        let mut player_state = playing("org.mpris.MediaPlayer2.mpv", "Song");
+        player_state.length = Some(Duration::from_secs(200));
+        let json = render_json(&args, &mut player_state);
+        assert!(json["tooltip"].as_str().unwrap().contains('\n'), "{}", json);
+
+        player_state.update_playback_dbus("Stopped".to_string(), Some(Duration::ZERO));
+        let json = render_json(&args, &mut player_state);
+        assert!(json.get("tooltip").is_none(), "{}", json);
+    }
+
+    #[test]
+    fn strip_affixes_removes_the_first_matching_prefix_and_suffix() {
+        let prefixes = ["Now Playing: ".to_string(), "Now ".to_string()];
+        let suffixes = [" - YouTube".to_string(), " - YouTube Music".to_string()];
+        for (title, expected) in [
+            ("Now Playing: Song - YouTube", "Song"),
+            ("Now Song", "Song"),
+            ("Song - YouTube Music", "Song"),
+            ("Song - YouTube - YouTube", "Song - YouTube"),
+            ("Playing: Song", "Playing: Song"),
+            // Nothing would be left, so the title stays.
+            ("Now Playing:  - YouTube", "Now Playing:  - YouTube"),
+        ] {
+            assert_eq!(strip_affixes(title, &prefixes, &suffixes), expected, "{:?}", title);
+        }
+    }
+
+    #[test]
+    fn title_fallback_moves_the_first_non_empty_field() {
+        let format = ["--format", "{title} - {artist} - {album}"];
+        for (fallback, artist, album, expected) in [
+            ("album,artist", "Host", "Show", "Show - Host"),
+            ("album,artist", "Host", "", "Host"),
+            ("artist,album", "Host", "Show", "Host - Show"),
+            ("album", "Host", "", "Host"),
+            ("artist", "", "Show", "Show"),
+        ] {
+            let mut player_state = playing("org.mpris.MediaPlayer2.mpv", "");
+            player_state.artist = artist.to_string();
+            player_state.album = album.to_string();
+            let args = [&format[..], &["--title-fallback", fallback]].concat();
+            assert_eq!(render(&args, &mut player_state), expected, "{} {:?} {:?}", fallback, artist, album);
+        }
+    }
+
+    #[test]
+    fn format_stopped_names_the_player_that_played() {
+        let args = ["--format-stopped", "Last: {title} ({player})"];
+        let mut player_state = playing("org.mpris.MediaPlayer2.mpv", "Song");
+        player_state.update_playback_dbus("Stopped".to_string(), Some(Duration::ZERO));
+        assert_eq!(render(&args, &mut player_state), "Last: Song (mpv)");
+
+        // Still known once the player has gone away.
+        player_state.update_from_metadata(&Default::default());
+        player_state.set_service("");
+        assert_eq!(render(&args, &mut player_state), "Last: Song (mpv)");
+    }
+
+    #[test]
+    fn held_pause_keeps_scrolling_as_playing() {
+        let config = Config::from_args(&["--status-debounce", "500", "--width", "5", "--format", "{title}", "--no-icon"]);
+        let mut player_state = playing("org.mpris.MediaPlayer2.mpv", "A title longer than five");
+        // What the handler does with a Paused status inside the debounce window.
+        player_state.pending_pause = Some((std::time::Instant::now(), Some(Duration::ZERO)));
+        assert!(ticks(&config, &player_state));
+
+        let mut scroll_state = ScrollState::new();
+        let first = render_status(&config, &mut player_state, &mut scroll_state, true);
+        let second = render_status(&config, &mut player_state, &mut scroll_state, true);
+        assert_ne!(first, second);
+        let json: serde_json::Value = serde_json::from_str(&second).unwrap();
+        assert_eq!(json["class"], "playing");
     }
 }